@@ -0,0 +1,10 @@
+CREATE TABLE pending_replies (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    chat_id BIGINT NOT NULL,
+    business_id VARCHAR,
+    sender_id VARCHAR NOT NULL,
+    message TEXT NOT NULL,
+    lang VARCHAR NOT NULL,
+    send_at TIMESTAMPTZ NOT NULL
+);
\ No newline at end of file
@@ -0,0 +1,10 @@
+CREATE TABLE scheduled_replies (
+    id BIGSERIAL PRIMARY KEY,
+    chat_id BIGINT NOT NULL,
+    business_id VARCHAR,
+    text TEXT NOT NULL,
+    send_at TIMESTAMPTZ NOT NULL,
+    typing_sent BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX scheduled_replies_send_at_idx ON scheduled_replies (send_at);
\ No newline at end of file
@@ -0,0 +1,10 @@
+CREATE TABLE llm_usage (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    model VARCHAR NOT NULL,
+    provider VARCHAR NOT NULL,
+    tokens INT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX llm_usage_user_id_created_at_idx ON llm_usage (user_id, created_at);
\ No newline at end of file
@@ -0,0 +1,36 @@
+-- Add down migration script here
+ALTER TABLE users ADD COLUMN openai JSONB NOT NULL DEFAULT '{}'::JSONB;
+
+UPDATE users
+SET openai = jsonb_build_object(
+    'config', jsonb_strip_nulls(jsonb_build_object(
+        'api_key', s.api_key,
+        'model', s.model,
+        'prompt', s.prompt,
+        'max_message_length', s.max_message_length,
+        'max_total_tokens_spent', s.max_total_tokens_spent,
+        'max_tokens', s.max_tokens,
+        'conversation', CASE WHEN s.cache_duration IS NOT NULL OR s.char_limit IS NOT NULL
+            THEN jsonb_build_object('cache_duration', s.cache_duration, 'char_limit', s.char_limit) END,
+        'chatting', jsonb_build_object(
+            'answer_pause', jsonb_build_array(s.answer_pause_min, s.answer_pause_max),
+            'footer', s.footer
+        ),
+        'sandbox', CASE WHEN s.sandbox_pause IS NOT NULL THEN jsonb_build_object('pause', s.sandbox_pause) END,
+        'language', s.language,
+        'fallbacks', jsonb_strip_nulls(jsonb_build_object(
+            'error', s.fallback_error,
+            'too_long', s.fallback_too_long,
+            'unsupported_media', s.fallback_unsupported_media,
+            'budget_exhausted', s.fallback_budget_exhausted
+        )),
+        'fallback_models', s.fallback_models
+    )),
+    'spent_tokens', COALESCE(p.spent_tokens, 0)
+)
+FROM user_settings s
+LEFT JOIN user_spend p ON p.user_id = s.user_id
+WHERE s.user_id = users.id;
+
+DROP TABLE IF EXISTS user_spend;
+DROP TABLE IF EXISTS user_settings;
@@ -0,0 +1,61 @@
+CREATE TABLE user_settings (
+    user_id BIGINT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    version INT NOT NULL DEFAULT 0,
+    api_key JSONB,
+    model VARCHAR NOT NULL DEFAULT 'gpt-3.5-turbo',
+    prompt TEXT,
+    max_message_length INT NOT NULL DEFAULT 4000,
+    max_total_tokens_spent BIGINT NOT NULL DEFAULT 1000000,
+    max_tokens INT NOT NULL DEFAULT 300,
+    cache_duration BIGINT,
+    char_limit INT,
+    answer_pause_min INT NOT NULL DEFAULT 0,
+    answer_pause_max INT NOT NULL DEFAULT 0,
+    footer VARCHAR DEFAULT '[ai generated answer]',
+    sandbox_pause BOOLEAN,
+    language VARCHAR,
+    fallback_error TEXT,
+    fallback_too_long TEXT,
+    fallback_unsupported_media TEXT,
+    fallback_budget_exhausted TEXT,
+    fallback_models JSONB NOT NULL DEFAULT '[]'::JSONB,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE user_spend (
+    user_id BIGINT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    spent_tokens BIGINT NOT NULL DEFAULT 0
+);
+
+INSERT INTO user_settings (
+    user_id, api_key, model, prompt, max_message_length, max_total_tokens_spent, max_tokens,
+    cache_duration, char_limit, answer_pause_min, answer_pause_max, footer, sandbox_pause, language,
+    fallback_error, fallback_too_long, fallback_unsupported_media, fallback_budget_exhausted, fallback_models
+)
+SELECT
+    id,
+    c->'api_key',
+    COALESCE(c->>'model', 'gpt-3.5-turbo'),
+    c->>'prompt',
+    COALESCE((c->>'max_message_length')::INT, 4000),
+    COALESCE((c->>'max_total_tokens_spent')::BIGINT, 1000000),
+    COALESCE((c->>'max_tokens')::INT, 300),
+    (c->'conversation'->>'cache_duration')::BIGINT,
+    (c->'conversation'->>'char_limit')::INT,
+    COALESCE((c->'chatting'->'answer_pause'->>0)::INT, 0),
+    COALESCE((c->'chatting'->'answer_pause'->>1)::INT, 0),
+    CASE WHEN c ? 'chatting' THEN c->'chatting'->>'footer' ELSE '[ai generated answer]' END,
+    (c->'sandbox'->>'pause')::BOOLEAN,
+    c->>'language',
+    c->'fallbacks'->>'error',
+    c->'fallbacks'->>'too_long',
+    c->'fallbacks'->>'unsupported_media',
+    c->'fallbacks'->>'budget_exhausted',
+    COALESCE(c->'fallback_models', '[]'::JSONB)
+FROM (SELECT id, openai->'config' AS c FROM users) AS legacy;
+
+INSERT INTO user_spend (user_id, spent_tokens)
+SELECT id, COALESCE((openai->>'spent_tokens')::BIGINT, 0)
+FROM users;
+
+ALTER TABLE users DROP COLUMN openai;
\ No newline at end of file
@@ -0,0 +1,5 @@
+ALTER TABLE users
+    ADD COLUMN status VARCHAR NOT NULL DEFAULT 'active',
+    ADD COLUMN disabled_at TIMESTAMPTZ;
+
+CREATE INDEX users_disabled_at_idx ON users (disabled_at) WHERE status = 'disabled';
\ No newline at end of file
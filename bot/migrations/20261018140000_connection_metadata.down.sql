@@ -0,0 +1,8 @@
+-- Add down migration script here
+ALTER TABLE users
+    DROP COLUMN IF EXISTS can_reply,
+    DROP COLUMN IF EXISTS connected_at,
+    DROP COLUMN IF EXISTS first_name,
+    DROP COLUMN IF EXISTS last_name,
+    DROP COLUMN IF EXISTS username,
+    DROP COLUMN IF EXISTS language_code;
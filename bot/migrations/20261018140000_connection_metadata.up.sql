@@ -0,0 +1,7 @@
+ALTER TABLE users
+    ADD COLUMN can_reply BOOLEAN NOT NULL DEFAULT TRUE,
+    ADD COLUMN connected_at TIMESTAMPTZ,
+    ADD COLUMN first_name VARCHAR,
+    ADD COLUMN last_name VARCHAR,
+    ADD COLUMN username VARCHAR,
+    ADD COLUMN language_code VARCHAR;
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS scheduled_replies_message_idx;
+ALTER TABLE scheduled_replies DROP COLUMN IF EXISTS message_id;
+ALTER TABLE pending_replies DROP COLUMN IF EXISTS message_id;
@@ -0,0 +1,5 @@
+ALTER TABLE pending_replies ADD COLUMN message_id BIGINT;
+
+ALTER TABLE scheduled_replies ADD COLUMN message_id BIGINT;
+
+CREATE INDEX scheduled_replies_message_idx ON scheduled_replies (business_id, chat_id, message_id);
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE scheduled_replies DROP COLUMN IF EXISTS reply_to_message_id;
+ALTER TABLE user_settings DROP COLUMN IF EXISTS reply_threading;
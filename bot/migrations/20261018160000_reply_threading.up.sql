@@ -0,0 +1,3 @@
+ALTER TABLE user_settings ADD COLUMN reply_threading BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE scheduled_replies ADD COLUMN reply_to_message_id BIGINT;
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE scheduled_replies ADD COLUMN footer TEXT;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Add down migration script here
+UPDATE users SET status = 'disabled', disabled_at = NOW() WHERE status = 'blocked';
+ALTER TABLE user_spend DROP COLUMN IF EXISTS token_limit;
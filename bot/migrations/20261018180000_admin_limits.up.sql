@@ -0,0 +1 @@
+ALTER TABLE user_spend ADD COLUMN token_limit BIGINT;
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE llm_usage DROP COLUMN IF EXISTS cost;
+ALTER TABLE users DROP COLUMN IF EXISTS plan_expires_at, DROP COLUMN IF EXISTS plan;
+DROP TABLE IF EXISTS plans;
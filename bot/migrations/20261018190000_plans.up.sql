@@ -0,0 +1,25 @@
+CREATE TABLE plans (
+    id VARCHAR PRIMARY KEY,
+    allowed_models JSONB NOT NULL DEFAULT '[]'::JSONB,
+    monthly_tokens BIGINT,
+    monthly_cost DOUBLE PRECISION,
+    max_history_length INT,
+    features JSONB NOT NULL DEFAULT '[]'::JSONB,
+    trial_days INT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO plans (id, allowed_models, monthly_tokens, max_history_length, trial_days)
+VALUES ('trial', '["gpt-3.5-turbo", "gpt-4o-mini"]'::JSONB, 200000, 2000, 14);
+
+INSERT INTO plans (id, allowed_models, monthly_tokens, max_history_length)
+VALUES ('basic', '["gpt-3.5-turbo", "gpt-4o-mini"]'::JSONB, 2000000, 4000);
+
+INSERT INTO plans (id, monthly_tokens, features)
+VALUES ('pro', 10000000, '["voice", "rag"]'::JSONB);
+
+ALTER TABLE users
+    ADD COLUMN plan VARCHAR REFERENCES plans (id) ON DELETE SET NULL,
+    ADD COLUMN plan_expires_at TIMESTAMPTZ;
+
+ALTER TABLE llm_usage ADD COLUMN cost DOUBLE PRECISION NOT NULL DEFAULT 0;
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS payments;
+ALTER TABLE user_spend DROP COLUMN IF EXISTS token_balance;
+ALTER TABLE plans DROP COLUMN IF EXISTS period_days, DROP COLUMN IF EXISTS price_stars;
@@ -0,0 +1,21 @@
+ALTER TABLE plans
+    ADD COLUMN price_stars INT,
+    ADD COLUMN period_days INT NOT NULL DEFAULT 30;
+
+ALTER TABLE user_spend ADD COLUMN token_balance BIGINT NOT NULL DEFAULT 0;
+
+CREATE TABLE payments (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    telegram_payment_charge_id VARCHAR NOT NULL UNIQUE,
+    provider_payment_charge_id VARCHAR NOT NULL,
+    payload VARCHAR NOT NULL,
+    currency VARCHAR NOT NULL,
+    amount INT NOT NULL,
+    plan VARCHAR,
+    plan_expires_at TIMESTAMPTZ,
+    tokens BIGINT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX payments_user_id_created_at_idx ON payments (user_id, created_at);
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE llm_usage DROP COLUMN IF EXISTS shared_key;
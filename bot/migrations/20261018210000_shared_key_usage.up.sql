@@ -0,0 +1 @@
+ALTER TABLE llm_usage ADD COLUMN shared_key BOOLEAN NOT NULL DEFAULT FALSE;
\ No newline at end of file
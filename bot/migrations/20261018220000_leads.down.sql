@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS leads;
+
+ALTER TABLE user_settings DROP COLUMN IF EXISTS lead_fields;
@@ -0,0 +1,11 @@
+ALTER TABLE user_settings ADD COLUMN lead_fields JSONB NOT NULL DEFAULT '[]'::JSONB;
+
+CREATE TABLE leads (
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    sender_id VARCHAR NOT NULL,
+    fields JSONB NOT NULL DEFAULT '{}'::JSONB,
+    completed_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, sender_id)
+);
\ No newline at end of file
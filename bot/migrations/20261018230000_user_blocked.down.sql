@@ -0,0 +1,4 @@
+-- Add down migration script here
+UPDATE users SET status = 'blocked' WHERE blocked;
+
+ALTER TABLE users DROP COLUMN IF EXISTS blocked;
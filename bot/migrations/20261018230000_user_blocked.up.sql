@@ -0,0 +1,6 @@
+ALTER TABLE users ADD COLUMN blocked BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE users
+SET blocked = TRUE,
+    status = CASE WHEN disabled_at IS NULL THEN 'active' ELSE 'disabled' END
+WHERE status = 'blocked';
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE scheduled_replies DROP COLUMN IF EXISTS attempts, DROP COLUMN IF EXISTS sent_parts, DROP COLUMN IF EXISTS claimed_until;
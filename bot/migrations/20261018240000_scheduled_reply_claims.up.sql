@@ -0,0 +1,4 @@
+ALTER TABLE scheduled_replies
+    ADD COLUMN attempts INT NOT NULL DEFAULT 0,
+    ADD COLUMN sent_parts INT NOT NULL DEFAULT 0,
+    ADD COLUMN claimed_until TIMESTAMPTZ;
\ No newline at end of file
@@ -0,0 +1,208 @@
+use std::env;
+use std::sync::OnceLock;
+use chrono::Utc;
+use sqlx::{Pool, Postgres};
+use tgbot::api::Client;
+use tgbot::types::SendMessage;
+use tokio::time::{sleep, Duration};
+use crate::{db, logging};
+use crate::error::Result;
+use crate::i18n::{t, tf, Lang};
+use crate::plan::Plan;
+
+const COMMANDS: [&str; 12] = [
+    "/admin", "/businesses", "/usage", "/limit", "/block", "/unblock", "/broadcast", "/errors",
+    "/plans", "/edit_plan", "/assign_plan", "/payments",
+];
+const MAX_LISTED_BUSINESSES: i64 = 50;
+const USAGE_DAYS: i32 = 30;
+const MAX_ERROR_LENGTH: usize = 300;
+const MAX_LISTED_PAYMENTS: i64 = 30;
+/// Keeps broadcasts under the Telegram limit of about 30 messages per second.
+const BROADCAST_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Telegram user ids of operators, from the comma-separated `ADMIN_IDS`.
+fn admin_ids() -> &'static [i64] {
+    static IDS: OnceLock<Vec<i64>> = OnceLock::new();
+    IDS.get_or_init(|| {
+        env::var("ADMIN_IDS").unwrap_or_default()
+            .split(',')
+            .filter_map(|value| value.trim().parse().ok())
+            .collect()
+    })
+}
+
+pub fn is_admin(id: i64) -> bool {
+    admin_ids().contains(&id)
+}
+
+pub fn is_command(text: &str) -> bool {
+    text.split_whitespace().next().is_some_and(|command| COMMANDS.contains(&command))
+}
+
+pub async fn execute(pool: &Pool<Postgres>, client: &Client, admin_id: i64, command: &str, lang: Lang) -> Result<String> {
+    let parts: Vec<&str> = command.split_whitespace().collect();
+
+    let response = match parts.as_slice() {
+        ["/admin"] => t(lang, "admin-help"),
+        ["/businesses"] => {
+            let businesses = db::load_businesses(pool, MAX_LISTED_BUSINESSES).await?;
+            match businesses.is_empty() {
+                true => t(lang, "no-businesses"),
+                false => businesses.iter()
+                    .map(|business| tf(lang, "business-line", &[
+                        ("id", &business.id.to_string()),
+                        ("name", business.first_name.as_deref().unwrap_or("---")),
+                        ("username", business.username.as_deref().unwrap_or("---")),
+                        ("status", &business.status),
+                        ("can_reply", if business.can_reply { "on" } else { "off" }),
+                        ("spent", &business.spent_tokens.to_string()),
+                        ("limit", &business.token_limit.map_or("---".to_string(), |limit| limit.to_string())),
+                    ]))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            }
+        }
+        ["/usage", id] => {
+            let id = parse_id(id)?;
+            let user = db::load_user_from_chat_id(pool, id).await?.ok_or("user-not-found")?;
+            let mut lines = vec![tf(lang, "usage-summary", &[
+                ("spent", &user.get_openai_spent_tokens().to_string()),
+                ("limit", &user.get_token_limit().to_string()),
+            ])];
+            let usage = db::load_usage(pool, id, USAGE_DAYS).await?;
+            if usage.is_empty() {
+                lines.push(tf(lang, "usage-empty", &[("days", &USAGE_DAYS.to_string())]));
+            }
+            lines.extend(usage.iter().map(|row| tf(lang, "usage-line", &[
+                ("model", &row.model),
+                ("provider", &row.provider),
+                ("key", &t(lang, if row.shared_key { "key-shared" } else { "key-own" })),
+                ("answers", &row.answers.to_string()),
+                ("tokens", &row.tokens.to_string()),
+            ])));
+            lines.join("\n")
+        }
+        ["/limit", id, value] => {
+            let token_limit = match *value {
+                "[none]" => None,
+                value => Some(value.parse::<i64>().ok().filter(|value| *value >= 0).ok_or("invalid-token-amount")?),
+            };
+            if !db::set_token_limit(pool, parse_id(id)?, token_limit).await? {
+                return Err("user-not-found".into());
+            }
+            t(lang, "option-updated")
+        }
+        ["/block", id] | ["/unblock", id] => {
+            if !db::set_blocked(pool, parse_id(id)?, parts[0] == "/block").await? {
+                return Err("user-not-found".into());
+            }
+            t(lang, "option-updated")
+        }
+        ["/broadcast", ..] => {
+            let text = command.replacen("/broadcast", "", 1).trim().to_string();
+            if text.is_empty() {
+                return Err("empty-broadcast".into());
+            }
+            let ids = db::load_active_user_ids(pool).await?;
+            let response = tf(lang, "broadcast-started", &[("count", &ids.len().to_string())]);
+            tokio::spawn(broadcast(client.clone(), admin_id, ids, text, lang));
+            response
+        }
+        ["/errors"] => {
+            let errors = logging::recent_errors();
+            match errors.is_empty() {
+                true => t(lang, "no-errors"),
+                false => errors.iter()
+                    .map(|(time, text)| {
+                        let text: String = text.chars().take(MAX_ERROR_LENGTH).collect();
+                        format!("{} {text}", time.format("%Y-%m-%d %H:%M:%S"))
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n\n"),
+            }
+        }
+        ["/plans"] => {
+            db::load_plans(pool).await?.iter()
+                .map(|plan| plan.describe(lang))
+                .collect::<Vec<_>>()
+                .join("\n\n")
+        }
+        ["/edit_plan", name, field, value @ ..] => {
+            let mut plan = db::load_plan(pool, name).await?.unwrap_or_else(|| Plan::new(name));
+            plan.set(field, &value.join(" "))?;
+            db::save_plan(pool, &plan).await?;
+            plan.describe(lang)
+        }
+        ["/assign_plan", id, "[none]"] => {
+            if !db::set_plan(pool, parse_id(id)?, None, None).await? {
+                return Err("user-not-found".into());
+            }
+            t(lang, "option-updated")
+        }
+        ["/assign_plan", id, name, days @ ..] if days.len() <= 1 => {
+            let plan = db::load_plan(pool, name).await?.ok_or("plan-not-found")?;
+            let expires_at = match days.first() {
+                Some(days) => {
+                    let days: i64 = days.parse().ok().filter(|days| *days > 0).ok_or("invalid-plan-value")?;
+                    Some(Utc::now() + chrono::Duration::days(days))
+                }
+                None => None,
+            };
+            if !db::set_plan(pool, parse_id(id)?, Some(plan.get_name()), expires_at).await? {
+                return Err("user-not-found".into());
+            }
+            t(lang, "option-updated")
+        }
+        ["/payments", id @ ..] if id.len() <= 1 => {
+            let user_id = id.first().map(|id| parse_id(id)).transpose()?;
+            let payments = db::load_payments(pool, user_id, MAX_LISTED_PAYMENTS).await?;
+            match payments.is_empty() {
+                true => t(lang, "no-payments"),
+                false => payments.iter()
+                    .map(|payment| tf(lang, "payment-line", &[
+                        ("date", &payment.created_at.format("%Y-%m-%d %H:%M").to_string()),
+                        ("user_id", &payment.user_id.to_string()),
+                        ("amount", &payment.amount.to_string()),
+                        ("product", &match (&payment.plan, payment.tokens) {
+                            (Some(plan), _) => match payment.plan_expires_at {
+                                Some(expires_at) => format!("{plan} → {}", expires_at.format("%Y-%m-%d")),
+                                None => plan.clone(),
+                            },
+                            (None, Some(tokens)) => tokens.to_string(),
+                            (None, None) => "---".to_string(),
+                        }),
+                        ("charge_id", &payment.telegram_payment_charge_id),
+                    ]))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            }
+        }
+        _ => t(lang, "unknown-command")
+    };
+
+    Ok(response)
+}
+
+fn parse_id(value: &str) -> Result<i64> {
+    value.parse().map_err(|_| "invalid-user-id".into())
+}
+
+/// Sends the announcement to every active owner, then reports the result to the operator.
+async fn broadcast(client: Client, admin_id: i64, ids: Vec<i64>, text: String, lang: Lang) {
+    let mut failed = 0;
+    for id in &ids {
+        if let Err(e) = client.execute(SendMessage::new(*id, text.clone())).await {
+            tracing::warn!(error = %e, user_id = id, "Failed send broadcast");
+            failed += 1;
+        }
+        sleep(BROADCAST_INTERVAL).await;
+    }
+    let report = tf(lang, "broadcast-finished", &[
+        ("sent", &(ids.len() - failed).to_string()),
+        ("failed", &failed.to_string()),
+    ]);
+    if let Err(e) = client.execute(SendMessage::new(admin_id, report)).await {
+        tracing::error!(error = %e, "Failed report broadcast result");
+    }
+}
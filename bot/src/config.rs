@@ -0,0 +1,59 @@
+use std::env;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use tokio::time::Duration;
+
+/// Operator tunables, read from the environment once at startup.
+#[derive(Debug)]
+pub struct Config {
+    /// `MAX_CONCURRENT_UPDATES`, updates handled in parallel across chats.
+    pub max_concurrent_updates: usize,
+    /// `MAX_LLM_CALLS_PER_USER`, parallel model calls of one business.
+    pub max_llm_calls_per_user: usize,
+    /// `LLM_MAX_RETRIES`, retries of transient model failures, at most 10.
+    pub llm_max_retries: u32,
+    /// `LLM_TIMEOUT` in seconds, per model request.
+    pub llm_timeout: Duration,
+    /// `SHUTDOWN_TIMEOUT` in seconds, how long in-flight replies are awaited on shutdown.
+    pub shutdown_timeout: Duration,
+    /// `DISABLED_RETENTION_DAYS`, how long disabled owners are kept.
+    pub retention_days: i32,
+    /// `TOPUP_TOKENS` on sale for `TOPUP_PRICE_STARS`, `None` if either is zero.
+    pub topup: Option<(i64, i32)>,
+    /// `FALLBACK_BASE_URLS`, comma-separated self-hosted endpoints owners may use as fallbacks.
+    pub fallback_base_urls: Vec<String>,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        let topup = (parse_env("TOPUP_TOKENS", 1_000_000_i64), parse_env("TOPUP_PRICE_STARS", 100));
+        Self {
+            max_concurrent_updates: parse_env("MAX_CONCURRENT_UPDATES", 64_usize).max(1),
+            max_llm_calls_per_user: parse_env("MAX_LLM_CALLS_PER_USER", 4_usize).max(1),
+            llm_max_retries: parse_env("LLM_MAX_RETRIES", 2_u32).min(10),
+            llm_timeout: Duration::from_secs(parse_env("LLM_TIMEOUT", 60)),
+            shutdown_timeout: Duration::from_secs(parse_env("SHUTDOWN_TIMEOUT", 30)),
+            retention_days: parse_env("DISABLED_RETENTION_DAYS", 30),
+            topup: Some(topup).filter(|(tokens, price)| *tokens > 0 && *price > 0),
+            fallback_base_urls: env::var("FALLBACK_BASE_URLS").unwrap_or_default()
+                .split(',')
+                .map(|url| url.trim().trim_end_matches('/').to_string())
+                .filter(|url| !url.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn allows_base_url(&self, base_url: &str) -> bool {
+        self.fallback_base_urls.iter().any(|allowed| allowed == base_url.trim_end_matches('/'))
+    }
+}
+
+pub fn get() -> &'static Config {
+    static CONFIG: OnceLock<Config> = OnceLock::new();
+    CONFIG.get_or_init(Config::from_env)
+}
+
+/// Value of an environment variable, the default if it is unset or invalid.
+fn parse_env<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
@@ -1,20 +1,24 @@
 use std::env;
-use redis::{AsyncCommands, RedisResult};
+use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
 use chrono::Utc;
+use crate::error::{Error, Result};
 
 pub const DEFAULT_CACHE_DURATION: i64 = 60 * 10;
 pub const DEFAULT_CHAR_LIMIT: usize = 10_000;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     pub role: String,
     pub content: String,
+    /// Telegram id of the customer message, for the answer the id of the message it answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<i64>,
 }
 
 impl Message {
-    fn new(role: &str, content: &str) -> Self {
-        Self { role: role.to_string(), content: content.to_string() }
+    fn new(role: &str, content: &str, message_id: Option<i64>) -> Self {
+        Self { role: role.to_string(), content: content.to_string(), message_id }
     }
 }
 
@@ -26,19 +30,24 @@ pub struct ConversationManager {
 }
 
 impl ConversationManager {
-    pub async fn default() -> Self {
-        Self::new(DEFAULT_CACHE_DURATION, DEFAULT_CHAR_LIMIT).await
+    pub fn default() -> Result<Self> {
+        Self::new(DEFAULT_CACHE_DURATION, DEFAULT_CHAR_LIMIT)
     }
 
-    async fn new(cache_duration: i64, char_limit: usize) -> Self {
-        let redis_url = env::var("REDIS_URL").expect("REDIS_URL must be set");
-        let client = redis::Client::open(redis_url).unwrap();
-        ConversationManager {
+    fn new(cache_duration: i64, char_limit: usize) -> Result<Self> {
+        let redis_url = env::var("REDIS_URL").map_err(|_| Error::internal("REDIS_URL must be set"))?;
+        let client = redis::Client::open(redis_url)?;
+        Ok(ConversationManager {
             client,
             cache_duration,
             char_limit,
             prefix: "history".to_string(),
-        }
+        })
+    }
+
+    /// Histories of the customers of one business owner.
+    pub fn for_owner(owner_id: i64) -> Result<Self> {
+        Ok(Self { prefix: format!("history:{owner_id}"), ..Self::default()? })
     }
 
     pub fn with_cache_duration(mut self, value: i64) -> Self {
@@ -51,24 +60,42 @@ impl ConversationManager {
         self
     }
 
-    pub async fn store_message(&self, user_id: &str, role: &str, content: &str, timestamp: Option<i64>) -> RedisResult<()> {
+    pub async fn ping(&self) -> Result<()> {
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+        redis::cmd("PING").query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Deletes every history of the owner.
+    pub async fn clear(&self) -> Result<()> {
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+        let mut keys: Vec<String> = vec![];
+        let mut iter = conn.scan_match::<_, String>(format!("{}:*", self.prefix)).await?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        drop(iter);
+        if !keys.is_empty() {
+            conn.del::<_, ()>(keys).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn store_message(&self, user_id: &str, message: &Message, timestamp: Option<i64>) -> Result<()> {
         let mut conn = self.client.get_multiplexed_async_connection().await?;
         let key = format!("{}:{}", self.prefix, user_id);
         let timestamp = timestamp.unwrap_or(Utc::now().timestamp_millis());
 
-        let message_json = serde_json::to_string(&Message {
-            role: role.to_string(),
-            content: content.to_string(),
-        }).unwrap();
+        let message_json = serde_json::to_string(message)?;
 
         // Add message to sorted set with timestamp as the score
-        conn.zadd(&key, message_json, timestamp).await?;
-        conn.expire(&key, self.cache_duration).await?;
+        conn.zadd::<_, _, _, ()>(&key, message_json, timestamp).await?;
+        conn.expire::<_, ()>(&key, self.cache_duration).await?;
 
         Ok(())
     }
 
-    pub async fn get_conversation(&self, user_id: &str, current_message_length: usize) -> RedisResult<Vec<Message>> {
+    pub async fn get_conversation(&self, user_id: &str, current_message_length: usize) -> Result<Vec<Message>> {
         let mut conn = self.client.get_multiplexed_async_connection().await?;
         let key = format!("{}:{}", self.prefix, user_id);
         let mut total_length = current_message_length;
@@ -83,7 +110,15 @@ impl ConversationManager {
             }
 
             let message_json = &batch[0];
-            let message: Message = serde_json::from_str(message_json).unwrap();
+            let message: Message = match serde_json::from_str(message_json) {
+                Ok(message) => message,
+                Err(e) => {
+                    tracing::error!(error = %e, "Dropped malformed history entry");
+                    conn.zrem::<_, _, ()>(&key, message_json).await?;
+                    cursor += 1;
+                    continue;
+                }
+            };
             let message_length = message_json.len();
 
             if total_length + message_length <= self.char_limit {
@@ -91,7 +126,7 @@ impl ConversationManager {
                 trimmed_conversation.push(message);
             } else {
                 // Remove the old message that exceeds the limit
-                conn.zrem(&key, message_json).await?;
+                conn.zrem::<_, _, ()>(&key, message_json).await?;
             }
 
             cursor += 1;
@@ -103,13 +138,51 @@ impl ConversationManager {
         Ok(trimmed_conversation)
     }
 
-    pub async fn process_message<F, Fut>(&self, sender_id: &str, message: &str, func: F) -> Result<Option<String>, String>
+    /// Replaces the text of a customer message kept in the history, returns `false` if it is not there.
+    pub async fn edit_message(&self, user_id: &str, message_id: i64, content: &str) -> Result<bool> {
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+        let key = format!("{}:{}", self.prefix, user_id);
+        let entries: Vec<(String, f64)> = conn.zrange_withscores(&key, 0, -1).await?;
+
+        let mut edited = false;
+        for (message_json, timestamp) in entries {
+            let Ok(mut message) = serde_json::from_str::<Message>(&message_json) else { continue; };
+            if message.role != "user" || message.message_id != Some(message_id) {
+                continue;
+            }
+            message.content = content.to_string();
+            conn.zrem::<_, _, ()>(&key, &message_json).await?;
+            conn.zadd::<_, _, _, ()>(&key, serde_json::to_string(&message)?, timestamp).await?;
+            edited = true;
+        }
+        Ok(edited)
+    }
+
+    /// Removes a customer message from the history, with `with_answer` also the answer to it.
+    pub async fn remove_message(&self, user_id: &str, message_id: i64, with_answer: bool) -> Result<()> {
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+        let key = format!("{}:{}", self.prefix, user_id);
+        let entries: Vec<String> = conn.zrange(&key, 0, -1).await?;
+
+        for message_json in entries {
+            let Ok(message) = serde_json::from_str::<Message>(&message_json) else { continue; };
+            if message.message_id == Some(message_id) && (with_answer || message.role == "user") {
+                conn.zrem::<_, _, ()>(&key, &message_json).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn process_message<F, Fut>(&self, sender_id: &str, message: &str, message_id: Option<i64>, func: F) -> std::result::Result<Option<String>, String>
         where
             F: Fn(Vec<Message>) -> Fut,
-            Fut: std::future::Future<Output=Result<Option<String>, String>>,
+            Fut: std::future::Future<Output=std::result::Result<Option<String>, String>>,
     {
-        let mut history = self.get_conversation(&sender_id, message.len()).await.unwrap_or(vec![]);
-        history.push(Message::new("user", message));
+        let mut history = self.get_conversation(sender_id, message.len()).await.unwrap_or_else(|e| {
+            tracing::error!(error = %e, "Failed load history");
+            vec![]
+        });
+        history.push(Message::new("user", message, message_id));
         let timestamp = Some(Utc::now().timestamp_millis());
         let answer = match func(history).await {
             Ok(Some(answer)) => answer,
@@ -120,8 +193,8 @@ impl ConversationManager {
             ("user", message, timestamp),
             ("assistant", &answer, None)
         ].into_iter() {
-            if let Err(e) = self.store_message(sender_id, role, message, ts).await {
-                log::error!("Failed store message:\n{e:?}");
+            if let Err(e) = self.store_message(sender_id, &Message::new(role, message, message_id), ts).await {
+                tracing::error!(error = %e, "Failed store message");
             }
         }
 
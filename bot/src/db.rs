@@ -1,92 +1,322 @@
 use std::env;
+use chrono::{DateTime, Utc};
 use serde_json::Value;
-use sqlx::{Error, Pool, Postgres};
+use sqlx::{Pool, Postgres, Transaction};
 use sqlx::migrate::MigrateError;
 use sqlx::postgres::PgPoolOptions;
-use crate::user::{User, Openai};
+use tgbot::types::SuccessfulPayment;
+use tokio::time::Duration;
+use crate::dialogue::ChatResponse;
+use crate::error::{Error, Result};
+use crate::i18n::Lang;
+use crate::plan::{self, Plan, Subscription};
+use crate::queue::{ClaimedReply, ScheduledReply, CLAIM_SECONDS, TYPING_LEAD_SECONDS};
+use crate::shutdown::PendingReply;
+use crate::user::{Connection, OpenaiConfig, Spend, User};
 
 pub struct UserRow {
     id: i64,
-    business_id: String,
-    openai: Value,
+    can_reply: bool,
+    blocked: bool,
+    spent_tokens: i64,
+    token_limit: Option<i64>,
+    token_balance: i64,
+    plan: Option<String>,
+    plan_expires_at: Option<DateTime<Utc>>,
 }
 
-impl From<UserRow> for User {
-    fn from(row: UserRow) -> Self {
-        User::new(
-            row.id,
-            row.business_id,
-            row.openai.into(),
-        )
+/// Operator-defined plan, converted from and into `Plan`.
+pub struct PlanRow {
+    pub id: String,
+    pub allowed_models: Value,
+    pub monthly_tokens: Option<i64>,
+    pub monthly_cost: Option<f64>,
+    pub max_history_length: Option<i32>,
+    pub features: Value,
+    pub trial_days: Option<i32>,
+    pub price_stars: Option<i32>,
+    pub period_days: i32,
+}
+
+/// Connected business as listed to the operator.
+pub struct BusinessRow {
+    pub id: i64,
+    pub first_name: Option<String>,
+    pub username: Option<String>,
+    pub status: String,
+    pub can_reply: bool,
+    pub spent_tokens: i64,
+    pub token_limit: Option<i64>,
+}
+
+/// Entry of the payments ledger.
+pub struct PaymentRow {
+    pub user_id: i64,
+    pub telegram_payment_charge_id: String,
+    pub amount: i32,
+    pub plan: Option<String>,
+    pub plan_expires_at: Option<DateTime<Utc>>,
+    pub tokens: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Details collected from one customer, `fields` maps the owner's field names to values.
+pub struct LeadRow {
+    pub sender_id: String,
+    pub fields: Value,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub updated_at: DateTime<Utc>,
+}
+
+pub struct UsageRow {
+    pub model: String,
+    pub provider: String,
+    pub shared_key: bool,
+    pub answers: i64,
+    pub tokens: i64,
+}
+
+/// Settings of a business owner, converted from and into `OpenaiConfig`.
+#[derive(PartialEq)]
+pub struct SettingsRow {
+    pub version: i32,
+    pub api_key: Option<Value>,
+    pub model: String,
+    pub prompt: Option<String>,
+    pub max_message_length: i32,
+    pub max_total_tokens_spent: i64,
+    pub max_tokens: i32,
+    pub cache_duration: Option<i64>,
+    pub char_limit: Option<i32>,
+    pub answer_pause_min: i32,
+    pub answer_pause_max: i32,
+    pub footer: Option<String>,
+    pub sandbox_pause: Option<bool>,
+    pub language: Option<String>,
+    pub fallback_error: Option<String>,
+    pub fallback_too_long: Option<String>,
+    pub fallback_unsupported_media: Option<String>,
+    pub fallback_budget_exhausted: Option<String>,
+    pub fallback_models: Value,
+    pub reply_threading: bool,
+    pub lead_fields: Value,
+}
+
+pub struct PendingReplyRow {
+    user_id: i64,
+    chat_id: i64,
+    business_id: Option<String>,
+    sender_id: String,
+    message_id: Option<i64>,
+    message: String,
+    lang: String,
+    send_at: DateTime<Utc>,
+}
+
+impl From<PendingReplyRow> for PendingReply {
+    fn from(row: PendingReplyRow) -> Self {
+        PendingReply {
+            user_id: row.user_id,
+            chat_id: row.chat_id,
+            business_id: row.business_id,
+            sender_id: row.sender_id,
+            message_id: row.message_id,
+            message: row.message,
+            lang: Lang::from_code(&row.lang).unwrap_or_default(),
+            send_at: row.send_at,
+        }
     }
 }
 
-pub async fn create_pool() -> Pool<Postgres> {
+pub struct ScheduledReplyRow {
+    id: i64,
+    attempts: i32,
+    sent_parts: i32,
+    chat_id: i64,
+    business_id: Option<String>,
+    message_id: Option<i64>,
+    reply_to_message_id: Option<i64>,
+    text: String,
+    footer: Option<String>,
+    send_at: DateTime<Utc>,
+}
+
+impl From<ScheduledReplyRow> for ClaimedReply {
+    fn from(row: ScheduledReplyRow) -> Self {
+        ClaimedReply {
+            id: row.id,
+            attempts: row.attempts,
+            sent_parts: row.sent_parts as usize,
+            reply: ScheduledReply {
+                chat_id: row.chat_id,
+                business_id: row.business_id,
+                message_id: row.message_id,
+                reply_to_message_id: row.reply_to_message_id,
+                text: row.text,
+                footer: row.footer,
+                send_at: row.send_at,
+            },
+        }
+    }
+}
+
+/// Connects lazily, so the status server can report Postgres as down while the bot waits for it.
+pub fn create_pool() -> Result<Pool<Postgres>> {
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgPoolOptions::new()
+    Ok(PgPoolOptions::new()
         .max_connections(5)
-        .connect(&database_url)
-        .await
-        .expect("Failed to create pool")
+        .connect_lazy(&database_url)?)
 }
 
-pub async fn insert_or_update_user(pool: &Pool<Postgres>, id: i64, business_id: &str) -> Result<(), Error> {
-    let openai: Value = Openai::default().into();
-    sqlx::query!(
+/// Creates the owner on the trial plan or restores a disabled one, refreshing connection rights and profile.
+/// Returns false if the owner is blocked by the operator, blocked owners stay blocked.
+pub async fn insert_or_update_user(pool: &Pool<Postgres>, connection: &Connection) -> Result<bool> {
+    let id = connection.user_id;
+    let mut transaction = pool.begin().await?;
+    let row = sqlx::query!(
         r#"
-        INSERT INTO users (id, business_id, openai)
-        VALUES ($1, $2, $3)
+        INSERT INTO users (
+            id, business_id, can_reply, connected_at, first_name, last_name, username, language_code,
+            plan, plan_expires_at
+        )
+        VALUES (
+            $1, $2, $3, $4, $5, $6, $7, $8,
+            (SELECT id FROM plans WHERE id = $9),
+            (SELECT NOW() + make_interval(days => trial_days) FROM plans WHERE id = $9)
+        )
         ON CONFLICT (id)
         DO UPDATE SET
-            business_id = EXCLUDED.business_id
+            business_id = EXCLUDED.business_id,
+            can_reply = EXCLUDED.can_reply,
+            connected_at = EXCLUDED.connected_at,
+            first_name = EXCLUDED.first_name,
+            last_name = EXCLUDED.last_name,
+            username = EXCLUDED.username,
+            language_code = EXCLUDED.language_code,
+            status = 'active',
+            disabled_at = NULL
+        RETURNING NOT blocked AS "active!"
         "#,
         id,
-        business_id,
-        openai,
+        connection.business_id,
+        connection.can_reply,
+        connection.connected_at,
+        connection.first_name,
+        connection.last_name,
+        connection.username,
+        connection.language_code,
+        plan::get_trial_plan(),
     )
-    .execute(pool)
+        .fetch_one(&mut *transaction)
+        .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO user_settings (user_id)
+        VALUES ($1)
+        ON CONFLICT DO NOTHING
+        "#,
+        id,
+    )
+    .execute(&mut *transaction)
     .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO user_spend (user_id)
+        VALUES ($1)
+        ON CONFLICT DO NOTHING
+        "#,
+        id,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
 
-    Ok(())
+    Ok(row.active)
+}
+
+async fn load_user(pool: &Pool<Postgres>, row: Option<UserRow>) -> Result<Option<User>> {
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let settings = sqlx::query_as!(
+        SettingsRow,
+        r#"
+        SELECT version, api_key, model, prompt, max_message_length, max_total_tokens_spent, max_tokens,
+            cache_duration, char_limit, answer_pause_min, answer_pause_max, footer, sandbox_pause, language,
+            fallback_error, fallback_too_long, fallback_unsupported_media, fallback_budget_exhausted, fallback_models,
+            reply_threading, lead_fields
+        FROM user_settings
+        WHERE user_id = $1
+        "#,
+        row.id
+    )
+        .fetch_optional(pool)
+        .await?;
+    let config = match settings {
+        Some(settings) => settings.try_into()?,
+        None => OpenaiConfig::default(),
+    };
+
+    let subscription = match &row.plan {
+        Some(name) => load_plan(pool, name).await?.map(|plan| Subscription { plan, expires_at: row.plan_expires_at }),
+        None => None,
+    };
+
+    let spend = Spend {
+        spent_tokens: row.spent_tokens,
+        token_limit: row.token_limit,
+        token_balance: row.token_balance,
+    };
+
+    Ok(Some(User::new(row.id, row.can_reply, row.blocked, config, spend, subscription)))
 }
 
-pub async fn load_user_from_chat_id(pool: &Pool<Postgres>, value: i64) -> Result<User, Error> {
+pub async fn load_user_from_chat_id(pool: &Pool<Postgres>, value: i64) -> Result<Option<User>> {
     let row = sqlx::query_as!(
         UserRow,
         r#"
-        SELECT id, business_id, openai
+        SELECT id, can_reply, blocked, COALESCE(spent_tokens, 0) AS "spent_tokens!", token_limit,
+            COALESCE(token_balance, 0) AS "token_balance!", plan, plan_expires_at
         FROM users
+        LEFT JOIN user_spend ON user_spend.user_id = users.id
         WHERE id = $1
         "#,
         value
     )
-        .fetch_one(pool)
+        .fetch_optional(pool)
         .await?;
 
-    Ok(row.into())
+    load_user(pool, row).await
 }
 
-pub async fn load_user_from_business_id(pool: &Pool<Postgres>, value: &str) -> Result<User, Error> {
+pub async fn load_user_from_business_id(pool: &Pool<Postgres>, value: &str) -> Result<Option<User>> {
     let row = sqlx::query_as!(
         UserRow,
         r#"
-        SELECT id, business_id, openai
+        SELECT id, can_reply, blocked, COALESCE(spent_tokens, 0) AS "spent_tokens!", token_limit,
+            COALESCE(token_balance, 0) AS "token_balance!", plan, plan_expires_at
         FROM users
+        LEFT JOIN user_spend ON user_spend.user_id = users.id
         WHERE business_id = $1
+            AND status = 'active'
+            AND NOT blocked
         "#,
         value
     )
-        .fetch_one(pool)
+        .fetch_optional(pool)
         .await?;
 
-    Ok(row.into())
+    load_user(pool, row).await
 }
 
-pub async fn delete_user_by_id(pool: &Pool<Postgres>, value: i64) -> Result<(), Error> {
+/// Keeps settings and spend of a disconnected owner, so they are restored on reconnect.
+pub async fn disable_user_by_id(pool: &Pool<Postgres>, value: i64) -> Result<()> {
     sqlx::query!(
         r#"
-        DELETE FROM users
+        UPDATE users
+        SET status = 'disabled', disabled_at = NOW()
         WHERE id = $1
+            AND status = 'active'
         "#,
         value
     )
@@ -96,17 +326,287 @@ pub async fn delete_user_by_id(pool: &Pool<Postgres>, value: i64) -> Result<(),
     Ok(())
 }
 
-pub async fn update_openai_by_id(pool: &Pool<Postgres>, id: i64, openai: Openai) -> Result<(), Error> {
-    let openai: Value = openai.into();
-
+/// Deletes the owner with settings, spend, usage and unanswered messages.
+pub async fn delete_user_by_id(pool: &Pool<Postgres>, value: i64) -> Result<()> {
     sqlx::query!(
+        r#"
+        WITH deleted AS (
+            DELETE FROM users
+            WHERE id = $1
+            RETURNING id, business_id
+        ), usage AS (
+            DELETE FROM llm_usage
+            WHERE user_id IN (SELECT id FROM deleted)
+        ), scheduled AS (
+            DELETE FROM scheduled_replies
+            WHERE business_id IN (SELECT business_id FROM deleted)
+                OR (business_id IS NULL AND chat_id IN (SELECT id FROM deleted))
+        )
+        DELETE FROM pending_replies
+        WHERE user_id IN (SELECT id FROM deleted)
+        "#,
+        value
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Deletes owners disabled for longer than `days`, returns their ids.
+pub async fn purge_disabled_users(pool: &Pool<Postgres>, days: i32) -> Result<Vec<i64>> {
+    let rows = sqlx::query!(
+        r#"
+        WITH purged AS (
+            DELETE FROM users
+            WHERE status = 'disabled'
+                AND disabled_at < NOW() - make_interval(days => $1)
+            RETURNING id, business_id
+        ), usage AS (
+            DELETE FROM llm_usage
+            WHERE user_id IN (SELECT id FROM purged)
+        ), pending AS (
+            DELETE FROM pending_replies
+            WHERE user_id IN (SELECT id FROM purged)
+        ), scheduled AS (
+            DELETE FROM scheduled_replies
+            WHERE business_id IN (SELECT business_id FROM purged)
+                OR (business_id IS NULL AND chat_id IN (SELECT id FROM purged))
+        )
+        SELECT id AS "id!"
+        FROM purged
+        "#,
+        days,
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows.into_iter().map(|row| row.id).collect())
+}
+
+/// Businesses ordered by the latest connection.
+pub async fn load_businesses(pool: &Pool<Postgres>, limit: i64) -> Result<Vec<BusinessRow>> {
+    let rows = sqlx::query_as!(
+        BusinessRow,
+        r#"
+        SELECT id, first_name, username, CASE WHEN blocked THEN 'blocked' ELSE status END AS "status!", can_reply,
+            COALESCE(spent_tokens, 0) AS "spent_tokens!", token_limit
+        FROM users
+        LEFT JOIN user_spend ON user_spend.user_id = users.id
+        ORDER BY connected_at DESC NULLS LAST
+        LIMIT $1
+        "#,
+        limit,
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows)
+}
+
+/// Answers and tokens per model over the last `days`.
+pub async fn load_usage(pool: &Pool<Postgres>, user_id: i64, days: i32) -> Result<Vec<UsageRow>> {
+    let rows = sqlx::query_as!(
+        UsageRow,
+        r#"
+        SELECT model, provider, shared_key, COUNT(*) AS "answers!", SUM(tokens) AS "tokens!"
+        FROM llm_usage
+        WHERE user_id = $1
+            AND created_at > NOW() - make_interval(days => $2)
+        GROUP BY model, provider, shared_key
+        ORDER BY 5 DESC
+        "#,
+        user_id,
+        days,
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows)
+}
+
+/// Sets the operator token limit, `None` removes it. Returns false if there is no such user.
+pub async fn set_token_limit(pool: &Pool<Postgres>, user_id: i64, token_limit: Option<i64>) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE user_spend
+        SET token_limit = $1
+        WHERE user_id = $2
+        "#,
+        token_limit,
+        user_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Blocks the owner or lifts the block. Returns false if there is no such user.
+pub async fn set_blocked(pool: &Pool<Postgres>, user_id: i64, blocked: bool) -> Result<bool> {
+    let result = sqlx::query!(
         r#"
         UPDATE users
-        SET openai = $1
+        SET blocked = $1
         WHERE id = $2
         "#,
-        openai,
+        blocked,
+        user_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn load_active_user_ids(pool: &Pool<Postgres>) -> Result<Vec<i64>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT id
+        FROM users
+        WHERE status = 'active'
+            AND NOT blocked
+        "#,
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows.into_iter().map(|row| row.id).collect())
+}
+
+/// Saves settings if nobody changed them since they were loaded, otherwise fails with `Error::Conflict`.
+pub async fn update_settings(pool: &Pool<Postgres>, id: i64, config: &OpenaiConfig) -> Result<()> {
+    let row = SettingsRow::try_from(config)?;
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO user_settings (
+            user_id, version, api_key, model, prompt, max_message_length, max_total_tokens_spent, max_tokens,
+            cache_duration, char_limit, answer_pause_min, answer_pause_max, footer, sandbox_pause, language,
+            fallback_error, fallback_too_long, fallback_unsupported_media, fallback_budget_exhausted, fallback_models,
+            reply_threading, lead_fields
+        )
+        VALUES ($1, $2 + 1, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22)
+        ON CONFLICT (user_id)
+        DO UPDATE SET
+            version = EXCLUDED.version,
+            api_key = EXCLUDED.api_key,
+            model = EXCLUDED.model,
+            prompt = EXCLUDED.prompt,
+            max_message_length = EXCLUDED.max_message_length,
+            max_total_tokens_spent = EXCLUDED.max_total_tokens_spent,
+            max_tokens = EXCLUDED.max_tokens,
+            cache_duration = EXCLUDED.cache_duration,
+            char_limit = EXCLUDED.char_limit,
+            answer_pause_min = EXCLUDED.answer_pause_min,
+            answer_pause_max = EXCLUDED.answer_pause_max,
+            footer = EXCLUDED.footer,
+            sandbox_pause = EXCLUDED.sandbox_pause,
+            language = EXCLUDED.language,
+            fallback_error = EXCLUDED.fallback_error,
+            fallback_too_long = EXCLUDED.fallback_too_long,
+            fallback_unsupported_media = EXCLUDED.fallback_unsupported_media,
+            fallback_budget_exhausted = EXCLUDED.fallback_budget_exhausted,
+            fallback_models = EXCLUDED.fallback_models,
+            reply_threading = EXCLUDED.reply_threading,
+            lead_fields = EXCLUDED.lead_fields,
+            updated_at = NOW()
+        WHERE user_settings.version = $2
+        "#,
+        id,
+        row.version,
+        row.api_key,
+        row.model,
+        row.prompt,
+        row.max_message_length,
+        row.max_total_tokens_spent,
+        row.max_tokens,
+        row.cache_duration,
+        row.char_limit,
+        row.answer_pause_min,
+        row.answer_pause_max,
+        row.footer,
+        row.sandbox_pause,
+        row.language,
+        row.fallback_error,
+        row.fallback_too_long,
+        row.fallback_unsupported_media,
+        row.fallback_budget_exhausted,
+        row.fallback_models,
+        row.reply_threading,
+        row.lead_fields,
+    )
+    .execute(pool)
+    .await?;
+
+    match result.rows_affected() {
+        0 => Err(Error::Conflict),
+        _ => Ok(()),
+    }
+}
+
+/// Stored API keys of all users, as raw JSON.
+pub async fn load_api_keys(pool: &Pool<Postgres>) -> Result<Vec<(i64, Value)>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT user_id, api_key AS "api_key!"
+        FROM user_settings
+        WHERE api_key IS NOT NULL
+        "#,
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows.into_iter().map(|row| (row.user_id, row.api_key)).collect())
+}
+
+/// Replaces the API key only if it is still `old`, returns whether it was replaced.
+pub async fn replace_api_key(pool: &Pool<Postgres>, id: i64, old: Value, new: Value) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE user_settings
+        SET api_key = $1, version = version + 1, updated_at = NOW()
+        WHERE user_id = $2
+            AND api_key = $3
+        "#,
+        new,
         id,
+        old,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn add_spends(pool: &Pool<Postgres>, id: i64, tokens_spent: i32) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE user_spend
+        SET spent_tokens = spent_tokens + $1::INT
+        WHERE user_id = $2
+        "#,
+        tokens_spent,
+        id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Records which model and provider produced an answer, and whether it was paid by the operator.
+pub async fn insert_llm_usage(pool: &Pool<Postgres>, user_id: i64, response: &ChatResponse) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO llm_usage (user_id, model, provider, tokens, cost, shared_key)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        user_id,
+        response.model,
+        response.provider,
+        response.tokens_spent as i32,
+        response.cost,
+        response.shared_key,
     )
     .execute(pool)
     .await?;
@@ -114,19 +614,121 @@ pub async fn update_openai_by_id(pool: &Pool<Postgres>, id: i64, openai: Openai)
     Ok(())
 }
 
+/// Tokens and cost on the operator key in the current calendar month.
+pub async fn load_monthly_usage(pool: &Pool<Postgres>, user_id: i64) -> Result<(i64, f64)> {
+    let row = sqlx::query!(
+        r#"
+        SELECT COALESCE(SUM(tokens), 0)::BIGINT AS "tokens!", COALESCE(SUM(cost), 0) AS "cost!"
+        FROM llm_usage
+        WHERE user_id = $1
+            AND shared_key
+            AND created_at >= date_trunc('month', NOW())
+        "#,
+        user_id,
+    )
+        .fetch_one(pool)
+        .await?;
 
-pub async fn add_spends(pool: &Pool<Postgres>, id: i64, tokens_spent: i32) -> Result<(), Error> {
+    Ok((row.tokens, row.cost))
+}
+
+pub async fn load_plan(pool: &Pool<Postgres>, name: &str) -> Result<Option<Plan>> {
+    let row = sqlx::query_as!(
+        PlanRow,
+        r#"
+        SELECT id, allowed_models, monthly_tokens, monthly_cost, max_history_length, features, trial_days,
+            price_stars, period_days
+        FROM plans
+        WHERE id = $1
+        "#,
+        name,
+    )
+        .fetch_optional(pool)
+        .await?;
+
+    row.map(Plan::try_from).transpose()
+}
+
+pub async fn load_plans(pool: &Pool<Postgres>) -> Result<Vec<Plan>> {
+    let rows = sqlx::query_as!(
+        PlanRow,
+        r#"
+        SELECT id, allowed_models, monthly_tokens, monthly_cost, max_history_length, features, trial_days,
+            price_stars, period_days
+        FROM plans
+        ORDER BY created_at, id
+        "#,
+    )
+        .fetch_all(pool)
+        .await?;
+
+    rows.into_iter().map(Plan::try_from).collect()
+}
+
+/// Creates the plan or replaces all its limits.
+pub async fn save_plan(pool: &Pool<Postgres>, plan: &Plan) -> Result<()> {
+    let row = PlanRow::try_from(plan)?;
     sqlx::query!(
         r#"
-        UPDATE users
-        SET openai = jsonb_set(
-            openai::jsonb,
-            '{spent_tokens}',
-            ((openai->>'spent_tokens')::int + $1)::text::jsonb
+        INSERT INTO plans (
+            id, allowed_models, monthly_tokens, monthly_cost, max_history_length, features, trial_days,
+            price_stars, period_days
         )
-        WHERE id = $2
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        ON CONFLICT (id)
+        DO UPDATE SET
+            allowed_models = EXCLUDED.allowed_models,
+            monthly_tokens = EXCLUDED.monthly_tokens,
+            monthly_cost = EXCLUDED.monthly_cost,
+            max_history_length = EXCLUDED.max_history_length,
+            features = EXCLUDED.features,
+            trial_days = EXCLUDED.trial_days,
+            price_stars = EXCLUDED.price_stars,
+            period_days = EXCLUDED.period_days
         "#,
-        tokens_spent,
+        row.id,
+        row.allowed_models,
+        row.monthly_tokens,
+        row.monthly_cost,
+        row.max_history_length,
+        row.features,
+        row.trial_days,
+        row.price_stars,
+        row.period_days,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Assigns the plan, `None` lifts all plan restrictions. Returns false if there is no such user.
+pub async fn set_plan(pool: &Pool<Postgres>, user_id: i64, plan: Option<&str>, expires_at: Option<DateTime<Utc>>) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE users
+        SET plan = $1, plan_expires_at = $2
+        WHERE id = $3
+        "#,
+        plan,
+        expires_at,
+        user_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Takes purchased tokens used past the plan quota, the balance does not go below zero.
+pub async fn spend_balance(pool: &Pool<Postgres>, id: i64, tokens: i32) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE user_spend
+        SET token_balance = GREATEST(token_balance - $1::INT, 0)
+        WHERE user_id = $2
+        "#,
+        tokens,
         id,
     )
     .execute(pool)
@@ -135,8 +737,503 @@ pub async fn add_spends(pool: &Pool<Postgres>, id: i64, tokens_spent: i32) -> Re
     Ok(())
 }
 
-pub async fn migrate(pool: &Pool<Postgres>) -> Result<(), MigrateError> {
+/// Adds the payment to the ledger, returns `None` if it was already recorded.
+async fn insert_payment(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: i64,
+    payment: &SuccessfulPayment,
+    plan: Option<&str>,
+    tokens: Option<i64>,
+) -> Result<Option<i64>> {
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO payments (
+            user_id, telegram_payment_charge_id, provider_payment_charge_id, payload, currency, amount, plan, tokens
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (telegram_payment_charge_id) DO NOTHING
+        RETURNING id
+        "#,
+        user_id,
+        payment.telegram_payment_charge_id,
+        payment.provider_payment_charge_id,
+        payment.invoice_payload,
+        payment.currency,
+        payment.total_amount as i32,
+        plan,
+        tokens,
+    )
+        .fetch_optional(&mut **transaction)
+        .await?;
+
+    Ok(row.map(|row| row.id))
+}
+
+/// Records the payment and extends the plan, or switches to it from now if another plan was active.
+/// Returns the new expiry time, `None` if the payment was already applied.
+pub async fn apply_plan_payment(
+    pool: &Pool<Postgres>,
+    user_id: i64,
+    payment: &SuccessfulPayment,
+    plan: &str,
+    period_days: i32,
+) -> Result<Option<DateTime<Utc>>> {
+    let mut transaction = pool.begin().await?;
+    let Some(payment_id) = insert_payment(&mut transaction, user_id, payment, Some(plan), None).await? else {
+        return Ok(None);
+    };
+    let row = sqlx::query!(
+        r#"
+        UPDATE users
+        SET plan = $1::VARCHAR,
+            plan_expires_at = CASE WHEN plan = $1::VARCHAR AND plan_expires_at > NOW() THEN plan_expires_at ELSE NOW() END
+                + make_interval(days => $2)
+        WHERE id = $3
+        RETURNING plan_expires_at AS "plan_expires_at!"
+        "#,
+        plan,
+        period_days,
+        user_id,
+    )
+        .fetch_optional(&mut *transaction)
+        .await?;
+    // The ledger keeps the payment even if there is nobody to apply it to
+    if let Some(row) = &row {
+        sqlx::query!(
+            r#"
+            UPDATE payments
+            SET plan_expires_at = $1
+            WHERE id = $2
+            "#,
+            row.plan_expires_at,
+            payment_id,
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+
+    row.map(|row| Some(row.plan_expires_at)).ok_or("payment-failed".into())
+}
+
+/// Records the payment and adds tokens to the balance. Returns false if the payment was already applied.
+pub async fn apply_tokens_payment(pool: &Pool<Postgres>, user_id: i64, payment: &SuccessfulPayment, tokens: i64) -> Result<bool> {
+    let mut transaction = pool.begin().await?;
+    if insert_payment(&mut transaction, user_id, payment, None, Some(tokens)).await?.is_none() {
+        return Ok(false);
+    }
+    let result = sqlx::query!(
+        r#"
+        UPDATE user_spend
+        SET token_balance = token_balance + $1
+        WHERE user_id = $2
+        "#,
+        tokens,
+        user_id,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    match result.rows_affected() {
+        0 => Err("payment-failed".into()),
+        _ => Ok(true),
+    }
+}
+
+/// Latest payments, of one user or of everybody.
+pub async fn load_payments(pool: &Pool<Postgres>, user_id: Option<i64>, limit: i64) -> Result<Vec<PaymentRow>> {
+    let rows = sqlx::query_as!(
+        PaymentRow,
+        r#"
+        SELECT user_id, telegram_payment_charge_id, amount, plan, plan_expires_at, tokens, created_at
+        FROM payments
+        WHERE $1::BIGINT IS NULL OR user_id = $1
+        ORDER BY created_at DESC
+        LIMIT $2
+        "#,
+        user_id,
+        limit,
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows)
+}
+
+pub async fn load_lead(pool: &Pool<Postgres>, user_id: i64, sender_id: &str) -> Result<Option<LeadRow>> {
+    let row = sqlx::query_as!(
+        LeadRow,
+        r#"
+        SELECT sender_id, fields, completed_at, updated_at
+        FROM leads
+        WHERE user_id = $1 AND sender_id = $2
+        "#,
+        user_id,
+        sender_id,
+    )
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row)
+}
+
+/// Merges new values into the lead of a customer, returns all its fields.
+pub async fn save_lead(pool: &Pool<Postgres>, user_id: i64, sender_id: &str, fields: &Value) -> Result<Value> {
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO leads (user_id, sender_id, fields)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id, sender_id)
+        DO UPDATE SET
+            fields = leads.fields || EXCLUDED.fields,
+            updated_at = NOW()
+        RETURNING fields
+        "#,
+        user_id,
+        sender_id,
+        fields,
+    )
+        .fetch_one(pool)
+        .await?;
+
+    Ok(row.fields)
+}
+
+/// Marks a lead complete, returns false if it already was.
+pub async fn complete_lead(pool: &Pool<Postgres>, user_id: i64, sender_id: &str) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE leads
+        SET completed_at = NOW()
+        WHERE user_id = $1 AND sender_id = $2 AND completed_at IS NULL
+        "#,
+        user_id,
+        sender_id,
+    )
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn load_leads(pool: &Pool<Postgres>, user_id: i64, limit: i64) -> Result<Vec<LeadRow>> {
+    let rows = sqlx::query_as!(
+        LeadRow,
+        r#"
+        SELECT sender_id, fields, completed_at, updated_at
+        FROM leads
+        WHERE user_id = $1
+        ORDER BY updated_at DESC
+        LIMIT $2
+        "#,
+        user_id,
+        limit,
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows)
+}
+
+pub async fn insert_pending_reply(pool: &Pool<Postgres>, reply: &PendingReply) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO pending_replies (user_id, chat_id, business_id, sender_id, message_id, message, lang, send_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+        reply.user_id,
+        reply.chat_id,
+        reply.business_id,
+        reply.sender_id,
+        reply.message_id,
+        reply.message,
+        reply.lang.code(),
+        reply.send_at,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn take_pending_replies(pool: &Pool<Postgres>) -> Result<Vec<PendingReply>> {
+    let rows = sqlx::query_as!(
+        PendingReplyRow,
+        r#"
+        DELETE FROM pending_replies
+        RETURNING user_id, chat_id, business_id, sender_id, message_id, message, lang, send_at
+        "#,
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows.into_iter().map(|row| row.into()).collect())
+}
+
+pub async fn insert_scheduled_reply(pool: &Pool<Postgres>, reply: &ScheduledReply) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO scheduled_replies (chat_id, business_id, message_id, reply_to_message_id, text, footer, send_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+        reply.chat_id,
+        reply.business_id,
+        reply.message_id,
+        reply.reply_to_message_id,
+        reply.text,
+        reply.footer,
+        reply.send_at,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Drops queued replies of a connection that can no longer send them.
+pub async fn delete_scheduled_replies_by_business_id(pool: &Pool<Postgres>, business_id: &str) -> Result<()> {
+    sqlx::query!(
+        r#"
+        DELETE FROM scheduled_replies
+        WHERE business_id = $1
+        "#,
+        business_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Removes the not yet sent reply to a customer message, returns its send time.
+pub async fn take_scheduled_reply(pool: &Pool<Postgres>, business_id: &str, chat_id: i64, message_id: i64) -> Result<Option<DateTime<Utc>>> {
+    let row = sqlx::query!(
+        r#"
+        DELETE FROM scheduled_replies
+        WHERE business_id = $1
+            AND chat_id = $2
+            AND message_id = $3
+            AND (claimed_until IS NULL OR claimed_until < NOW())
+        RETURNING send_at
+        "#,
+        business_id,
+        chat_id,
+        message_id,
+    )
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.map(|row| row.send_at))
+}
+
+/// Marks replies due within the typing lead, returning the chats to show typing in.
+pub async fn take_typing_due(pool: &Pool<Postgres>) -> Result<Vec<(i64, Option<String>)>> {
+    let rows = sqlx::query!(
+        r#"
+        UPDATE scheduled_replies
+        SET typing_sent = TRUE
+        WHERE NOT typing_sent
+            AND send_at <= NOW() + make_interval(secs => $1)
+        RETURNING chat_id, business_id
+        "#,
+        TYPING_LEAD_SECONDS as f64,
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows.into_iter().map(|row| (row.chat_id, row.business_id)).collect())
+}
+
+/// Claims due replies for sending; rows claimed by another worker are skipped until the claim expires,
+/// so a reply lost in a crash is sent again.
+pub async fn claim_due_replies(pool: &Pool<Postgres>) -> Result<Vec<ClaimedReply>> {
+    let rows = sqlx::query_as!(
+        ScheduledReplyRow,
+        r#"
+        UPDATE scheduled_replies
+        SET claimed_until = NOW() + make_interval(secs => $1)
+        WHERE id IN (
+            SELECT id
+            FROM scheduled_replies
+            WHERE send_at <= NOW()
+                AND (claimed_until IS NULL OR claimed_until < NOW())
+            ORDER BY send_at
+            LIMIT 100
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, attempts, sent_parts, chat_id, business_id, message_id, reply_to_message_id, text, footer, send_at
+        "#,
+        CLAIM_SECONDS as f64,
+    )
+        .fetch_all(pool)
+        .await?;
+
+    let mut replies: Vec<ClaimedReply> = rows.into_iter().map(|row| row.into()).collect();
+    replies.sort_by_key(|claimed| claimed.reply.send_at);
+    Ok(replies)
+}
+
+/// Remembers the parts of a split reply already sent, so a retry continues after them.
+pub async fn set_sent_parts(pool: &Pool<Postgres>, id: i64, sent_parts: usize) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE scheduled_replies
+        SET sent_parts = $2
+        WHERE id = $1
+        "#,
+        id,
+        sent_parts as i32,
+    )
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Releases the claim and schedules another attempt after `delay`.
+pub async fn retry_scheduled_reply(pool: &Pool<Postgres>, id: i64, delay: Duration) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE scheduled_replies
+        SET attempts = attempts + 1,
+            send_at = NOW() + make_interval(secs => $2),
+            claimed_until = NULL,
+            typing_sent = FALSE
+        WHERE id = $1
+        "#,
+        id,
+        delay.as_secs_f64(),
+    )
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Removes a reply from the queue once it is sent or given up.
+pub async fn delete_scheduled_reply(pool: &Pool<Postgres>, id: i64) -> Result<()> {
+    sqlx::query!(
+        r#"
+        DELETE FROM scheduled_replies
+        WHERE id = $1
+        "#,
+        id,
+    )
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn count_scheduled_replies(pool: &Pool<Postgres>) -> Result<i64> {
+    let row = sqlx::query!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM scheduled_replies
+        "#,
+    )
+        .fetch_one(pool)
+        .await?;
+
+    Ok(row.count)
+}
+
+pub async fn count_active_users(pool: &Pool<Postgres>) -> Result<i64> {
+    let row = sqlx::query!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM users
+        WHERE status = 'active'
+            AND NOT blocked
+        "#,
+    )
+        .fetch_one(pool)
+        .await?;
+
+    Ok(row.count)
+}
+
+pub async fn migrate(pool: &Pool<Postgres>) -> std::result::Result<(), MigrateError> {
     sqlx::migrate!("./migrations")
         .run(pool)
         .await
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn insert_user(pool: &Pool<Postgres>, id: i64, business_id: &str) {
+        sqlx::query("INSERT INTO users (id, business_id) VALUES ($1, $2)")
+            .bind(id)
+            .bind(business_id)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn insert_reply(pool: &Pool<Postgres>, chat_id: i64, business_id: Option<&str>) {
+        insert_scheduled_reply(pool, &ScheduledReply {
+            chat_id,
+            business_id: business_id.map(str::to_string),
+            message_id: None,
+            reply_to_message_id: None,
+            text: "reply".to_string(),
+            footer: None,
+            send_at: Utc::now(),
+        }).await.unwrap();
+    }
+
+    async fn load_replies(pool: &Pool<Postgres>) -> Vec<(i64, Option<String>)> {
+        sqlx::query_as("SELECT chat_id, business_id FROM scheduled_replies ORDER BY id")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    /// The owner is also a customer of another business, whose replies to them must stay queued.
+    async fn setup_owner_and_customer(pool: &Pool<Postgres>) {
+        insert_user(pool, 1, "owner").await;
+        insert_user(pool, 2, "other").await;
+        insert_reply(pool, 10, Some("owner")).await;
+        insert_reply(pool, 1, None).await;
+        insert_reply(pool, 1, Some("other")).await;
+    }
+
+    #[sqlx::test]
+    async fn claimed_reply_stays_queued_until_deleted(pool: Pool<Postgres>) {
+        insert_reply(&pool, 10, Some("owner")).await;
+
+        let claimed = claim_due_replies(&pool).await.unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert!(claim_due_replies(&pool).await.unwrap().is_empty());
+
+        retry_scheduled_reply(&pool, claimed[0].id, Duration::ZERO).await.unwrap();
+        let retried = claim_due_replies(&pool).await.unwrap();
+        assert_eq!((retried[0].id, retried[0].attempts), (claimed[0].id, 1));
+
+        delete_scheduled_reply(&pool, claimed[0].id).await.unwrap();
+        assert!(load_replies(&pool).await.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn delete_user_keeps_replies_of_other_businesses(pool: Pool<Postgres>) {
+        setup_owner_and_customer(&pool).await;
+
+        delete_user_by_id(&pool, 1).await.unwrap();
+
+        assert_eq!(load_replies(&pool).await, vec![(1, Some("other".to_string()))]);
+    }
+
+    #[sqlx::test]
+    async fn purge_keeps_replies_of_other_businesses(pool: Pool<Postgres>) {
+        setup_owner_and_customer(&pool).await;
+        sqlx::query("UPDATE users SET status = 'disabled', disabled_at = NOW() - INTERVAL '2 days' WHERE id = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(purge_disabled_users(&pool, 1).await.unwrap(), vec![1]);
+        assert_eq!(load_replies(&pool).await, vec![(1, Some("other".to_string()))]);
+    }
+}
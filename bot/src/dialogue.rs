@@ -1,19 +1,76 @@
+use std::sync::OnceLock;
 use async_openai::Client;
-use async_openai::config::OpenAIConfig;
-use async_openai::error::OpenAIError;
-use async_openai::types::{ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs};
+use async_openai::config::{OpenAIConfig, OPENAI_API_BASE};
+use async_openai::error::{ApiError, OpenAIError};
+use async_openai::types::{ChatCompletionNamedToolChoice, ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs, ChatCompletionTool, ChatCompletionToolArgs, ChatCompletionToolChoiceOption, ChatCompletionToolType, CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse, FunctionName, FunctionObjectArgs};
+use reqwest::StatusCode;
+use reqwest::header::RETRY_AFTER;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::Instrument;
 use crate::conversation::Message;
+use crate::error::{Error, Result};
+use crate::{config, metrics};
 use crate::user::{OpenaiConfig};
 
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const LEAD_FUNCTION: &str = "save_lead";
+const LEAD_PROMPT: &str = "Extract the customer's details from the conversation and call save_lead. \
+    Fill a field only with what the customer clearly stated, otherwise use null. Never invent values.";
+
 pub struct ChatResponse {
     pub message: String,
     pub tokens_spent: u32,
+    /// USD, zero for self-hosted models.
+    pub cost: f64,
+    /// Model and provider that produced the answer, may be a fallback.
+    pub model: String,
+    pub provider: String,
+    pub shared_key: bool,
+}
+
+/// An OpenAI-compatible endpoint serving one model.
+#[derive(Debug, Clone)]
+pub struct Provider {
+    pub model: String,
+    pub base_url: String,
+    pub api_key: Option<String>,
+    /// Called with the operator key, so the usage is metered against the plan quota.
+    pub shared_key: bool,
+}
+
+impl Provider {
+    pub fn openai(model: &str, api_key: Option<String>, shared_key: bool) -> Self {
+        Self { model: model.to_string(), base_url: OPENAI_API_BASE.to_string(), api_key, shared_key }
+    }
+
+    /// An endpoint from the operator allowlist, never called with a key.
+    pub fn self_hosted(model: &str, base_url: &str) -> Self {
+        Self { model: model.to_string(), base_url: base_url.to_string(), api_key: None, shared_key: false }
+    }
+
+    pub fn get_name(&self) -> &str {
+        let host = self.base_url.split("://").last().unwrap_or(&self.base_url);
+        host.split('/').next().unwrap_or(host)
+    }
+}
+
+#[derive(Deserialize)]
+struct WrappedError {
+    error: ApiError,
+}
+
+enum Attempt {
+    Done(CreateChatCompletionResponse),
+    Retry(OpenAIError, Option<Duration>),
+    Fail(OpenAIError),
 }
 
-#[derive(Debug)]
-pub enum OpenaiResponseError {
-    Openai(OpenAIError),
-    Message(String),
+fn http_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(reqwest::Client::new)
 }
 
 pub fn get_client(api_key: &str) -> Client<OpenAIConfig> {
@@ -21,74 +78,224 @@ pub fn get_client(api_key: &str) -> Client<OpenAIConfig> {
     Client::with_config(openai_config)
 }
 
-impl From<Message> for ChatCompletionRequestMessage {
-    fn from(value: Message) -> Self {
-        match value.role.as_str() {
+impl TryFrom<Message> for ChatCompletionRequestMessage {
+    type Error = Error;
+
+    fn try_from(value: Message) -> Result<Self> {
+        Ok(match value.role.as_str() {
             "assistant" => ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessageArgs::default()
                 .content(value.content)
-                .build()
-                .unwrap()),
+                .build()?),
             "user" => ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessageArgs::default()
                 .content(value.content)
-                .build()
-                .unwrap()),
-            unknown => panic!("Invalid type {unknown}")
-        }
+                .build()?),
+            unknown => return Err(Error::internal(format!("Invalid message role {unknown}")))
+        })
     }
 }
 
-pub async fn get_response(config: &OpenaiConfig, messages: Vec<Message>) -> Result<ChatResponse, OpenaiResponseError> {
-    let client = get_client(
-        &config.get_real_api_key()
-            .ok_or_else(|| OpenaiResponseError::Message("I don't know what to answer".to_string()))?
-    );
+/// Tries the configured model and then each fallback in order, retrying transient failures.
+pub async fn get_response(config: &OpenaiConfig, messages: Vec<Message>) -> Result<ChatResponse> {
+    complete(config, config.get_prompt(), messages, None).await
+}
+
+/// Asks the model to fill the lead fields from the conversation via a forced function call.
+/// The message of the response is the JSON object of the call arguments.
+pub async fn extract_lead(config: &OpenaiConfig, fields: &[String], messages: Vec<Message>) -> Result<ChatResponse> {
+    let properties: serde_json::Map<String, serde_json::Value> = fields.iter()
+        .map(|field| (field.clone(), json!({"type": ["string", "null"], "description": field})))
+        .collect();
+    let tool = ChatCompletionToolArgs::default()
+        .function(FunctionObjectArgs::default()
+            .name(LEAD_FUNCTION)
+            .description("Saves the details the customer provided")
+            .parameters(json!({
+                "type": "object",
+                "properties": properties,
+                "required": fields,
+                "additionalProperties": false,
+            }))
+            .build()?)
+        .build()?;
+    complete(config, Some(LEAD_PROMPT), messages, Some(tool)).await
+}
+
+/// With a tool the model is forced to call it, and the call arguments are returned as the message.
+async fn complete(
+    config: &OpenaiConfig,
+    prompt: Option<&str>,
+    messages: Vec<Message>,
+    tool: Option<ChatCompletionTool>,
+) -> Result<ChatResponse> {
+    let providers = config.get_providers();
+    if providers.iter().all(|provider| provider.api_key.is_none() && provider.base_url == OPENAI_API_BASE) {
+        return Err("missing-api-key".into());
+    }
 
     let mut chat_messages = vec![];
-    if let Some(prompt) = config.get_prompt() {
+    if let Some(prompt) = prompt {
         chat_messages.push(
             ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessageArgs::default()
                 .content(prompt)
-                .build()
-                .unwrap())
+                .build()?)
         )
     }
     for msg in messages.into_iter() {
-        chat_messages.push(msg.into());
+        chat_messages.push(msg.try_into()?);
+    }
+
+    let mut last_error = Error::internal("No providers");
+    for provider in providers {
+        if provider.api_key.is_none() && provider.base_url == OPENAI_API_BASE {
+            continue;
+        }
+        let mut args = CreateChatCompletionRequestArgs::default();
+        args.max_tokens(config.get_max_tokens())
+            .model(&provider.model)
+            .messages(chat_messages.clone());
+        if let Some(tool) = &tool {
+            args.tools(vec![tool.clone()])
+                .tool_choice(ChatCompletionToolChoiceOption::Named(ChatCompletionNamedToolChoice {
+                    r#type: ChatCompletionToolType::Function,
+                    function: FunctionName { name: tool.function.name.clone() },
+                }));
+        }
+        let request = args.build()?;
+
+        let started = Instant::now();
+        let span = tracing::info_span!("llm", model = %provider.model, provider = provider.get_name());
+        let result = create_with_retries(&provider, &request).instrument(span).await;
+        metrics::get().llm_latency
+            .with_label_values(&[&provider.model, if result.is_ok() { "ok" } else { "error" }])
+            .observe(started.elapsed().as_secs_f64());
+        match result {
+            Ok(response) => {
+                let (tokens_spent, cost) = match &response.usage {
+                    Some(u) => (u.total_tokens, get_cost(&provider, u.prompt_tokens, u.completion_tokens)),
+                    _ => (0, 0.0)
+                };
+                metrics::get().tokens.with_label_values(&[&provider.model]).inc_by(tokens_spent.into());
+                metrics::get().cost.with_label_values(&[&provider.model]).inc_by(cost);
+                return Ok(
+                    ChatResponse {
+                        message: match (response.choices.first(), &tool) {
+                            (Some(choice), None) => choice.clone().message.content.ok_or_else(|| Error::internal("No answer content"))?,
+                            (Some(choice), Some(_)) => choice.clone().message.tool_calls
+                                .and_then(|calls| calls.into_iter().next())
+                                .ok_or_else(|| Error::internal("No function call"))?
+                                .function.arguments,
+                            (None, _) => { return Err(Error::internal("No answer")); }
+                        },
+                        tokens_spent,
+                        cost,
+                        model: provider.model.clone(),
+                        provider: provider.get_name().to_string(),
+                        shared_key: provider.shared_key,
+                    }
+                );
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, model = %provider.model, provider = provider.get_name(), "Model failed");
+                last_error = e.into();
+            }
+        }
     }
 
-    let request = CreateChatCompletionRequestArgs::default()
-        .max_tokens(config.get_max_tokens())
-        .model(config.get_model())
-        .messages(chat_messages)
-        .build()
-        .unwrap();
-
-    let response = client.chat()
-        .create(request)
-        .await.map_err(|e| OpenaiResponseError::Openai(e))?;
-
-    Ok(
-        ChatResponse {
-            message: match response.choices.first() {
-                Some(choice) => choice.clone().message.content.unwrap(),
-                None => { return Err(OpenaiResponseError::Message("No answer".to_string())); }
-            },
-            tokens_spent: match response.usage {
-                Some(u) => u.total_tokens,
-                _ => 0
-            },
+    Err(last_error)
+}
+
+/// USD per million prompt and completion tokens of OpenAI models.
+const MODEL_PRICES: [(&str, f64, f64); 4] = [
+    ("gpt-3.5-turbo", 0.5, 1.5),
+    ("gpt-4-turbo", 10.0, 30.0),
+    ("gpt-4o", 5.0, 15.0),
+    ("gpt-4o-mini", 0.15, 0.6),
+];
+
+fn get_cost(provider: &Provider, prompt_tokens: u32, completion_tokens: u32) -> f64 {
+    if provider.base_url != OPENAI_API_BASE {
+        return 0.0;
+    }
+    MODEL_PRICES.iter()
+        .find(|(model, _, _)| *model == provider.model)
+        .map_or(0.0, |(_, prompt, completion)| {
+            (prompt * f64::from(prompt_tokens) + completion * f64::from(completion_tokens)) / 1_000_000.0
+        })
+}
+
+async fn create_with_retries(provider: &Provider, request: &CreateChatCompletionRequest) -> std::result::Result<CreateChatCompletionResponse, OpenAIError> {
+    let max_retries = config::get().llm_max_retries;
+    let mut attempt = 0;
+    loop {
+        match create(provider, request).await {
+            Attempt::Done(response) => return Ok(response),
+            Attempt::Fail(e) => return Err(e),
+            Attempt::Retry(e, _) if attempt >= max_retries => return Err(e),
+            Attempt::Retry(e, retry_after) => {
+                let backoff = retry_after.unwrap_or(BASE_BACKOFF.saturating_mul(2u32.saturating_pow(attempt))).min(MAX_BACKOFF);
+                tracing::info!(error = %e, ?backoff, "Retrying model");
+                sleep(backoff).await;
+                attempt += 1;
+            }
         }
-    )
+    }
 }
 
-pub async fn is_api_key_valid(api_key: &str) -> Result<bool, String> {
+async fn create(provider: &Provider, request: &CreateChatCompletionRequest) -> Attempt {
+    let mut builder = http_client()
+        .post(format!("{}/chat/completions", provider.base_url.trim_end_matches('/')))
+        .timeout(config::get().llm_timeout)
+        .json(request);
+    // Keys are only ever sent to OpenAI
+    if let Some(api_key) = provider.api_key.as_ref().filter(|_| provider.base_url == OPENAI_API_BASE) {
+        builder = builder.bearer_auth(api_key);
+    }
+
+    let response = match builder.send().await {
+        Ok(response) => response,
+        Err(e) if e.is_timeout() || e.is_connect() => return Attempt::Retry(OpenAIError::Reqwest(e), None),
+        Err(e) => return Attempt::Fail(OpenAIError::Reqwest(e)),
+    };
+    let status = response.status();
+    let retry_after = response.headers().get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs);
+    let bytes = match response.bytes().await {
+        Ok(bytes) => bytes,
+        Err(e) => return Attempt::Retry(OpenAIError::Reqwest(e), None),
+    };
+
+    if status.is_success() {
+        return match serde_json::from_slice(&bytes) {
+            Ok(response) => Attempt::Done(response),
+            Err(e) => Attempt::Fail(OpenAIError::JSONDeserialize(e)),
+        };
+    }
+
+    let error = serde_json::from_slice::<WrappedError>(&bytes)
+        .map(|wrapped| wrapped.error)
+        .unwrap_or_else(|_| ApiError {
+            message: format!("HTTP {status}: {}", String::from_utf8_lossy(&bytes)),
+            r#type: None,
+            param: None,
+            code: None,
+        });
+    let transient = match status {
+        StatusCode::TOO_MANY_REQUESTS => error.r#type.as_deref() != Some("insufficient_quota"),
+        status => status.is_server_error(),
+    };
+    match transient {
+        true => Attempt::Retry(OpenAIError::ApiError(error), retry_after),
+        false => Attempt::Fail(OpenAIError::ApiError(error)),
+    }
+}
+
+pub async fn is_api_key_valid(api_key: &str) -> Result<bool> {
     let client = get_client(api_key);
     match client.models().list().await {
         Ok(_) => Ok(true),
-        Err(OpenAIError::ApiError(err)) => match err.message.starts_with("Incorrect API key provided") {
-            true => Ok(false),
-            _ => Err(format!("{err:?}"))
-        }
-        Err(err) => Err(format!("{err:?}"))
+        Err(OpenAIError::ApiError(err)) if err.message.starts_with("Incorrect API key provided") => Ok(false),
+        Err(err) => Err(err.into())
     }
-}
\ No newline at end of file
+}
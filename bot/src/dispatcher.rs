@@ -0,0 +1,175 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tgbot::api::Client;
+use tgbot::handler::UpdateHandler;
+use tgbot::types::{GetUpdates, Update, UpdateType};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, timeout, Duration};
+use crate::config;
+
+const POLL_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_ERROR_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Runs updates of different chats concurrently, while updates of one chat are handled one by one
+/// in the order they were received.
+pub struct Dispatcher<H> {
+    handler: Arc<H>,
+    chats: Arc<Mutex<HashMap<String, VecDeque<Update>>>>,
+    permits: Arc<Semaphore>,
+    idle: Arc<Notify>,
+}
+
+impl<H> Dispatcher<H>
+where
+    H: UpdateHandler + Send + Sync + 'static,
+{
+    pub fn new(handler: H) -> Self {
+        Self {
+            handler: Arc::new(handler),
+            chats: Default::default(),
+            permits: Arc::new(Semaphore::new(config::get().max_concurrent_updates)),
+            idle: Default::default(),
+        }
+    }
+
+    /// Must be called in the order updates are received.
+    pub fn dispatch(&self, update: Update) {
+        let Some(key) = get_chat_key(&update) else {
+            let handler = self.handler.clone();
+            let permits = self.permits.clone();
+            tokio::spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                handler.handle(update).await
+            });
+            return;
+        };
+
+        let mut chats = self.chats.lock().unwrap();
+        if let Some(queue) = chats.get_mut(&key) {
+            queue.push_back(update);
+            return;
+        }
+        chats.insert(key.clone(), VecDeque::new());
+        drop(chats);
+
+        let handler = self.handler.clone();
+        let chats = self.chats.clone();
+        let permits = self.permits.clone();
+        let idle = self.idle.clone();
+        tokio::spawn(async move {
+            let mut next = Some(update);
+            while let Some(update) = next {
+                let _permit = permits.clone().acquire_owned().await;
+                // A panicking handler must not leave the chat queue stuck
+                let handler = handler.clone();
+                if let Err(e) = tokio::spawn(async move { handler.handle(update).await }).await {
+                    tracing::error!(error = %e, "Update handler failed");
+                }
+
+                let mut chats = chats.lock().unwrap();
+                next = chats.get_mut(&key).and_then(|queue| queue.pop_front());
+                if next.is_none() {
+                    chats.remove(&key);
+                    if chats.is_empty() {
+                        idle.notify_waiters();
+                    }
+                }
+            }
+        });
+    }
+
+    /// Waits up to `deadline` for queued updates to be handled.
+    pub async fn drain(&self, deadline: Duration) {
+        let wait = async {
+            loop {
+                let notified = self.idle.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                if self.chats.lock().unwrap().is_empty() {
+                    break;
+                }
+                notified.await;
+            }
+        };
+        if timeout(deadline, wait).await.is_err() {
+            let dropped: usize = self.chats.lock().unwrap().values().map(|queue| queue.len()).sum();
+            tracing::warn!("Shutdown deadline reached, {dropped} queued updates dropped");
+        }
+    }
+
+    /// Long polling loop feeding the dispatcher until `stop` resolves.
+    pub async fn poll<S>(&self, client: &Client, stop: S)
+    where
+        S: Future<Output = ()>,
+    {
+        tokio::pin!(stop);
+        let mut offset = 0;
+        loop {
+            let method = GetUpdates::default()
+                .with_offset(offset + 1)
+                .with_timeout(POLL_TIMEOUT);
+            let updates = tokio::select! {
+                _ = &mut stop => break,
+                result = client.execute(method) => match result {
+                    Ok(updates) => updates,
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed get updates");
+                        sleep(POLL_ERROR_TIMEOUT).await;
+                        continue;
+                    }
+                }
+            };
+            for update in updates {
+                offset = offset.max(update.id);
+                self.dispatch(update);
+            }
+        }
+
+        // Confirm updates already dispatched, so they are not delivered again after restart
+        if offset > 0 {
+            let _ = client.execute(
+                GetUpdates::default()
+                    .with_offset(offset + 1)
+                    .with_limit(1)
+                    .with_timeout(Duration::ZERO)
+            ).await;
+        }
+    }
+}
+
+fn get_chat_key(update: &Update) -> Option<String> {
+    let chat_id = update.get_chat_id()?;
+    Some(match &update.update_type {
+        UpdateType::BusinessMessage(message) | UpdateType::EditedBusinessMessage(message) => format!(
+            "{}:{}", message.business_connection_id.as_deref().unwrap_or_default(), chat_id
+        ),
+        UpdateType::DeletedBusinessMessages(deleted) => format!("{}:{}", deleted.business_connection_id, chat_id),
+        _ => chat_id.to_string(),
+    })
+}
+
+/// Bounds the number of parallel LLM calls per business user.
+pub struct LlmLimiter {
+    limit: usize,
+    semaphores: Mutex<HashMap<i64, Arc<Semaphore>>>,
+}
+
+impl Default for LlmLimiter {
+    fn default() -> Self {
+        Self {
+            limit: config::get().max_llm_calls_per_user,
+            semaphores: Default::default(),
+        }
+    }
+}
+
+impl LlmLimiter {
+    pub async fn acquire(&self, user_id: i64) -> OwnedSemaphorePermit {
+        let semaphore = self.semaphores.lock().unwrap()
+            .entry(user_id)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+            .clone();
+        semaphore.acquire_owned().await.expect("LLM semaphore is never closed")
+    }
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+use async_openai::error::OpenAIError;
+use redis::RedisError;
+use tgbot::api::ExecuteError;
+use crate::i18n::{t, Lang};
+use crate::metrics;
+
+#[derive(Debug)]
+pub enum Error {
+    Database(sqlx::Error),
+    Redis(RedisError),
+    Telegram(ExecuteError),
+    Provider(OpenAIError),
+    /// Settings were changed by someone else since they were loaded.
+    Conflict,
+    /// Invalid input, carries the message catalog key of the reply.
+    Localized(&'static str),
+    /// Unexpected state, described for the logs only.
+    Internal(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn internal(description: impl Into<String>) -> Self {
+        Error::Internal(description.into())
+    }
+
+    /// Reply shown to the person whose action failed.
+    pub fn user_message(&self, lang: Lang) -> String {
+        match self {
+            Error::Database(_) => t(lang, "error-database"),
+            Error::Redis(_) => t(lang, "error-redis"),
+            Error::Telegram(_) => t(lang, "error-telegram"),
+            Error::Provider(_) => t(lang, "error-provider"),
+            Error::Conflict => t(lang, "error-conflict"),
+            Error::Localized(key) => t(lang, key),
+            Error::Internal(_) => t(lang, "error-internal"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Database(e) => write!(f, "database: {e}"),
+            Error::Redis(e) => write!(f, "redis: {e}"),
+            Error::Telegram(e) => write!(f, "telegram: {e}"),
+            Error::Provider(e) => write!(f, "provider: {e}"),
+            Error::Conflict => write!(f, "conflict: settings were changed concurrently"),
+            Error::Localized(key) => write!(f, "invalid input: {key}"),
+            Error::Internal(e) => write!(f, "internal: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<sqlx::Error> for Error {
+    fn from(value: sqlx::Error) -> Self {
+        metrics::get().errors.with_label_values(&["postgres"]).inc();
+        Error::Database(value)
+    }
+}
+
+impl From<RedisError> for Error {
+    fn from(value: RedisError) -> Self {
+        metrics::get().errors.with_label_values(&["redis"]).inc();
+        Error::Redis(value)
+    }
+}
+
+impl From<ExecuteError> for Error {
+    fn from(value: ExecuteError) -> Self {
+        metrics::get().errors.with_label_values(&["telegram"]).inc();
+        Error::Telegram(value)
+    }
+}
+
+impl From<OpenAIError> for Error {
+    fn from(value: OpenAIError) -> Self {
+        Error::Provider(value)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Self {
+        Error::Internal(value.to_string())
+    }
+}
+
+/// Catalog keys returned by setters and checks.
+impl From<&'static str> for Error {
+    fn from(key: &'static str) -> Self {
+        Error::Localized(key)
+    }
+}
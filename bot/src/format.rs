@@ -0,0 +1,236 @@
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+
+/// Telegram limit for a message text.
+const MAX_MESSAGE_LENGTH: usize = 4_096;
+
+/// Converts model Markdown to Telegram HTML split into messages at paragraph boundaries.
+/// The footer goes to the last message only.
+pub fn render(markdown: &str, footer: Option<&str>) -> Vec<String> {
+    let mut blocks = render_blocks(markdown);
+    if let Some(footer) = footer {
+        blocks.push(escape(footer));
+    }
+
+    let mut messages = vec![];
+    let mut current = String::new();
+    for block in blocks {
+        if length(&block) > MAX_MESSAGE_LENGTH {
+            if !current.is_empty() {
+                messages.push(std::mem::take(&mut current));
+            }
+            let mut chunks = split_plain(&strip_tags(&block));
+            current = chunks.pop().unwrap_or_default();
+            messages.extend(chunks);
+            continue;
+        }
+        if !current.is_empty() && length(&current) + 2 + length(&block) > MAX_MESSAGE_LENGTH {
+            messages.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(&block);
+    }
+    if !current.is_empty() {
+        messages.push(current);
+    }
+    messages
+}
+
+/// Text of a rendered message without markup, to resend it when Telegram rejects the HTML.
+pub fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&amp;", "&")
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Telegram counts the length in UTF-16 code units.
+fn length(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Splits a plain text reply into messages, preferring line and then word boundaries.
+pub fn split(text: &str) -> Vec<String> {
+    split_with(text, str::to_string)
+}
+
+/// Splits text into escaped chunks, preferring line and then word boundaries.
+fn split_plain(text: &str) -> Vec<String> {
+    split_with(text, escape)
+}
+
+fn split_with(text: &str, escape: impl Fn(&str) -> String) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut current = String::new();
+    for line in text.split_inclusive('\n') {
+        let pieces: Vec<&str> = match length(&escape(line)) > MAX_MESSAGE_LENGTH {
+            true => line.split_inclusive(' ').collect(),
+            false => vec![line],
+        };
+        for piece in pieces {
+            let escaped = escape(piece);
+            if !current.is_empty() && length(&current) + length(&escaped) > MAX_MESSAGE_LENGTH {
+                chunks.push(std::mem::take(&mut current));
+            }
+            if length(&escaped) <= MAX_MESSAGE_LENGTH {
+                current.push_str(&escaped);
+                continue;
+            }
+            for c in piece.chars() {
+                let escaped = escape(c.encode_utf8(&mut [0; 4]));
+                if length(&current) + length(&escaped) > MAX_MESSAGE_LENGTH {
+                    chunks.push(std::mem::take(&mut current));
+                }
+                current.push_str(&escaped);
+            }
+        }
+    }
+    if !current.trim().is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Renders each top-level Markdown block into a self-contained piece of Telegram HTML.
+fn render_blocks(markdown: &str) -> Vec<String> {
+    let mut blocks = vec![];
+    let mut html = String::new();
+    let mut depth = 0;
+    let mut lists: Vec<Option<u64>> = vec![];
+
+    for event in Parser::new_ext(markdown, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS) {
+        match event {
+            Event::Start(tag) => {
+                depth += 1;
+                match tag {
+                    Tag::Heading { .. } | Tag::Strong => html.push_str("<b>"),
+                    Tag::Emphasis => html.push_str("<i>"),
+                    Tag::Strikethrough => html.push_str("<s>"),
+                    Tag::BlockQuote(_) => html.push_str("<blockquote>"),
+                    Tag::CodeBlock(CodeBlockKind::Fenced(language)) if !language.is_empty() => {
+                        html.push_str(&format!("<pre><code class=\"language-{}\">", escape(&language)));
+                    }
+                    Tag::CodeBlock(_) => html.push_str("<pre><code>"),
+                    Tag::Link { dest_url, .. } => html.push_str(&format!("<a href=\"{}\">", escape(&dest_url))),
+                    Tag::List(start) => {
+                        if !lists.is_empty() {
+                            html.push('\n');
+                        }
+                        lists.push(start);
+                    }
+                    Tag::Item => {
+                        if !html.is_empty() && !html.ends_with('\n') {
+                            html.push('\n');
+                        }
+                        html.push_str(&"  ".repeat(lists.len().saturating_sub(1)));
+                        match lists.last_mut() {
+                            Some(Some(number)) => {
+                                html.push_str(&format!("{number}. "));
+                                *number += 1;
+                            }
+                            _ => html.push_str("• "),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Event::End(tag) => {
+                depth -= 1;
+                if matches!(tag, TagEnd::BlockQuote(_) | TagEnd::CodeBlock) {
+                    html.truncate(html.trim_end_matches('\n').len());
+                }
+                match tag {
+                    TagEnd::Heading(_) | TagEnd::Strong => html.push_str("</b>"),
+                    TagEnd::Emphasis => html.push_str("</i>"),
+                    TagEnd::Strikethrough => html.push_str("</s>"),
+                    TagEnd::BlockQuote(_) => html.push_str("</blockquote>"),
+                    TagEnd::CodeBlock => html.push_str("</code></pre>"),
+                    TagEnd::Link => html.push_str("</a>"),
+                    TagEnd::List(_) => {
+                        lists.pop();
+                    }
+                    TagEnd::Paragraph if depth > 0 => html.push('\n'),
+                    _ => {}
+                }
+                if depth == 0 {
+                    let block = html.trim_end().to_string();
+                    html.clear();
+                    if !block.is_empty() {
+                        blocks.push(block);
+                    }
+                }
+            }
+            Event::Text(text) | Event::Html(text) | Event::InlineHtml(text) => html.push_str(&escape(&text)),
+            Event::Code(code) => html.push_str(&format!("<code>{}</code>", escape(&code))),
+            Event::SoftBreak | Event::HardBreak => html.push('\n'),
+            Event::Rule => blocks.push("———".to_string()),
+            Event::TaskListMarker(checked) => html.push_str(if checked { "☑ " } else { "☐ " }),
+            _ => {}
+        }
+    }
+    if !html.trim().is_empty() {
+        blocks.push(html.trim_end().to_string());
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_balanced(html: &str) -> bool {
+        ["b", "i", "s", "code", "pre", "a", "blockquote"].iter().all(|tag| {
+            html.matches(&format!("<{tag}>")).count() + html.matches(&format!("<{tag} ")).count()
+                == html.matches(&format!("</{tag}>")).count()
+        })
+    }
+
+    #[test]
+    fn length_counts_utf16_code_units() {
+        assert_eq!(length("abc"), 3);
+        assert_eq!(length("привет"), 6);
+        assert_eq!(length("😀"), 2);
+
+        let chunks = split(&"😀".repeat(3_000));
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|chunk| length(chunk) <= MAX_MESSAGE_LENGTH));
+        assert_eq!(chunks.concat(), "😀".repeat(3_000));
+    }
+
+    #[test]
+    fn split_messages_keep_tags_balanced() {
+        let markdown = "**Bold** text with *italic*, `code` and [a link](https://example.com).\n\n".repeat(200);
+        let messages = render(&markdown, None);
+        assert!(messages.len() > 1);
+        for message in &messages {
+            assert!(length(message) <= MAX_MESSAGE_LENGTH);
+            assert!(is_balanced(message), "unbalanced tags in {message}");
+        }
+
+        let messages = render(&format!("**{}**", "word ".repeat(2_000)), None);
+        assert!(messages.len() > 1);
+        assert!(messages.iter().all(|message| length(message) <= MAX_MESSAGE_LENGTH && !message.contains('<')));
+    }
+
+    #[test]
+    fn footer_goes_to_last_message_only() {
+        let markdown = "A paragraph of the answer.\n\n".repeat(400);
+        let messages = render(&markdown, Some("Sent by <bot>"));
+        let (last, rest) = messages.split_last().unwrap();
+        assert!(!rest.is_empty());
+        assert!(last.ends_with("Sent by &lt;bot&gt;"));
+        assert!(rest.iter().all(|message| !message.contains("Sent by")));
+    }
+}
@@ -0,0 +1,107 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use axum::{Extension, Json, Router};
+use axum::http::StatusCode;
+use axum::routing;
+use serde_json::{json, Value};
+use sqlx::{Pool, Postgres};
+use tgbot::api::Client;
+use tgbot::types::GetBot;
+use tokio::time::{sleep, timeout, Duration};
+use crate::conversation::ConversationManager;
+use crate::error::Result;
+use crate::status;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+struct Dependencies {
+    pool: Pool<Postgres>,
+    client: Client,
+    started: Arc<AtomicBool>,
+}
+
+/// Serves from the start of the process, `started` turns true once startup has waited for all dependencies.
+pub fn router(pool: Pool<Postgres>, client: Client, started: Arc<AtomicBool>) -> Router {
+    Router::new()
+        .route("/healthz", routing::get(|| async { "ok" }))
+        .route("/readyz", routing::get(ready))
+        .layer(Extension(Dependencies { pool, client, started }))
+}
+
+/// Ready when startup has finished and Postgres, Redis and Telegram all respond.
+async fn ready(Extension(dependencies): Extension<Dependencies>) -> (StatusCode, Json<Value>) {
+    let started = dependencies.started.load(Ordering::Relaxed);
+    let (postgres, redis, telegram) = tokio::join!(
+        check(check_postgres(&dependencies.pool)),
+        check(check_redis()),
+        check(check_telegram(&dependencies.client)),
+    );
+    let status = match started && postgres.is_ok() && redis.is_ok() && telegram.is_ok() {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let describe = |result: std::result::Result<(), String>| result.err().unwrap_or("ok".to_string());
+    (status, Json(json!({
+        "startup": if started { "ok" } else { "waiting for dependencies" },
+        "postgres": describe(postgres),
+        "redis": describe(redis),
+        "telegram": describe(telegram),
+    })))
+}
+
+async fn check<F>(future: F) -> std::result::Result<(), String>
+where
+    F: Future<Output = Result<()>>,
+{
+    match timeout(CHECK_TIMEOUT, future).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timeout".to_string()),
+    }
+}
+
+pub async fn check_postgres(pool: &Pool<Postgres>) -> Result<()> {
+    sqlx::query("SELECT 1").execute(pool).await?;
+    Ok(())
+}
+
+pub async fn check_redis() -> Result<()> {
+    ConversationManager::default()?.ping().await
+}
+
+pub async fn check_telegram(client: &Client) -> Result<()> {
+    client.execute(GetBot).await?;
+    Ok(())
+}
+
+/// Retries `connect` with backoff until it succeeds, so the bot waits for its dependencies on startup.
+pub async fn wait_for<T, F, Fut>(name: &str, mut connect: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut delay = INITIAL_RETRY_DELAY;
+    loop {
+        match connect().await {
+            Ok(value) => return value,
+            Err(e) => {
+                tracing::warn!(error = %e, "{name} is not available, retrying in {delay:?}");
+                sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+        }
+    }
+}
+
+/// Exit code of the container health check: 0 if the running bot answers `/healthz`.
+pub async fn probe() -> i32 {
+    let url = format!("http://127.0.0.1:{}/healthz", status::get_address().port());
+    match reqwest::Client::new().get(url).timeout(CHECK_TIMEOUT).send().await {
+        Ok(response) if response.status().is_success() => 0,
+        _ => 1,
+    }
+}
@@ -0,0 +1,291 @@
+use serde::{Deserialize, Serialize};
+use tgbot::types::User as TgUser;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lang {
+    #[default]
+    En,
+    Ru,
+}
+
+impl Lang {
+    pub const ALL: [Lang; 2] = [Lang::En, Lang::Ru];
+
+    /// Accepts both plain (`ru`) and regional (`ru-RU`) IETF codes, as sent by Telegram.
+    pub fn from_code(code: &str) -> Option<Self> {
+        let code = code.split(['-', '_']).next()?.to_lowercase();
+        Self::ALL.into_iter().find(|lang| lang.code() == code)
+    }
+
+    pub fn from_user(user: Option<&TgUser>) -> Self {
+        user.and_then(|user| user.language_code.as_deref())
+            .and_then(Self::from_code)
+            .unwrap_or_default()
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::Ru => "ru",
+        }
+    }
+
+    pub fn help_file(&self) -> &'static str {
+        match self {
+            Lang::En => "./files/help_text.txt",
+            Lang::Ru => "./files/help_text_ru.txt",
+        }
+    }
+
+    fn catalog(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Lang::En => EN,
+            Lang::Ru => RU,
+        }
+    }
+}
+
+/// Looks the key up in the language catalog, then in English; unknown keys are returned as is.
+pub fn t(lang: Lang, key: &str) -> String {
+    lookup(lang.catalog(), key)
+        .or_else(|| lookup(EN, key))
+        .unwrap_or(key)
+        .to_string()
+}
+
+pub fn tf(lang: Lang, key: &str, args: &[(&str, &str)]) -> String {
+    args.iter().fold(t(lang, key), |text, (name, value)| {
+        text.replace(&format!("{{{name}}}"), value)
+    })
+}
+
+fn lookup(catalog: &'static [(&'static str, &'static str)], key: &str) -> Option<&'static str> {
+    catalog.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+}
+
+const EN: &[(&str, &str)] = &[
+    ("connection-created", "created\nnow /help for info"),
+    ("connection-created-shared-key", "created\nanswers already work with the shared key of the service, set your own with /api_key if you like\n/help for info"),
+    ("connection-cannot-reply", "connected, but the bot is not allowed to reply\nopen Settings → Telegram Business → Chatbots and turn on \"Reply to messages\" for the bot, then /help for info"),
+    ("connection-disabled", "disabled\nyour settings are kept until you reconnect, send /forget_me to delete them"),
+    ("forget-me-confirm", "All your settings, API key and usage history will be deleted. Send /forget_me confirm to proceed"),
+    ("forgotten", "Your data has been deleted"),
+    ("only-for-business", "only for business\ncontact {contact}"),
+    ("only-text", "Only text"),
+    ("too-long-message", "too long message"),
+    ("no-answer", "I don't know what to answer"),
+    ("option-updated", "Option updated"),
+    ("unknown-command", "Unknown command"),
+    ("help-failed", "failed get help"),
+    ("error-database", "Failed to access storage, please try again later"),
+    ("error-redis", "Failed to access conversation history, please try again later"),
+    ("error-telegram", "Telegram request failed, please try again later"),
+    ("error-provider", "AI provider request failed, please try again later"),
+    ("error-conflict", "Settings were changed at the same time, please repeat the command"),
+    ("error-internal", "Something went wrong, please try again later"),
+    ("missing-api-key", "API key is not set, use /api_key"),
+    ("current-api-key", "Current API key: {value}"),
+    ("api-key-updated", "API key saved: {value}"),
+    ("default-api-key", "shared key of the service"),
+    ("current-history-timeout", "Current history timeout: {value} seconds"),
+    ("current-history-length", "Current history length: {value} symbols"),
+    ("current-answer-pause", "Current answer pause: {value} seconds"),
+    ("current-answer-pause-range", "Current answer pause: from {from} to {to} seconds"),
+    ("current-footer", "Current footer: {value}"),
+    ("current-model", "Current model: {value}"),
+    ("current-prompt", "Current prompt: {value}"),
+    ("current-max-message-length", "Current max message length: {value}"),
+    ("current-max-total-tokens-spent", "Current max total tokens spent: {value}"),
+    ("current-max-tokens", "Current max tokens: {value}"),
+    ("current-language", "Current language: {value}"),
+    ("current-reply-threading", "Reply threading: {value}"),
+    ("max-prompt-size", "Max prompt size is 4.000 symbols"),
+    ("invalid-history-timeout", "Invalid history_timeout"),
+    ("invalid-history-length", "Invalid history_length"),
+    ("invalid-length", "Invalid length"),
+    ("invalid-token-amount", "Invalid token amount"),
+    ("invalid-language", "Invalid language. Allowed values are: en, ru"),
+    ("invalid-reply-threading", "Invalid value. Allowed values are: on, off"),
+    ("invalid-api-key", "Invalid API key"),
+    ("api-key-check-failed", "Failed check API key"),
+    ("invalid-model", "Invalid model. Allowed values are: gpt-3.5-turbo, gpt-4-turbo, gpt-4o, gpt-4o-mini"),
+    ("current-fallback-models", "Current fallback models: {value}"),
+    ("invalid-fallback-model", "Invalid fallback model. Use an allowed model name or model@https://host/v1 for a self-hosted one"),
+    ("fallback-url-not-allowed", "This self-hosted endpoint is not allowed by the operator"),
+    ("too-many-fallback-models", "Maximum 3 fallback models"),
+    ("current-lead-fields", "Current lead fields: {value}"),
+    ("too-many-lead-fields", "Maximum 8 lead fields"),
+    ("lead-field-too-long", "A lead field name must be at most 50 symbols"),
+    ("no-leads", "No leads yet"),
+    ("lead-line", "{date} customer {customer}: {fields}"),
+    ("lead-line-complete", "{date} customer {customer} [complete]: {fields}"),
+    ("lead-completed", "New lead from customer {customer}:\n{fields}"),
+    ("prompt-too-long", "Prompt is too long. Maximum length is 4000 characters"),
+    ("max-message-length-too-long", "Max message length is too long. Maximum is 4000"),
+    ("history-timeout-too-long", "Maximum duration is 3,600 seconds"),
+    ("history-length-too-long", "Maximum limit is 10,000 symbols"),
+    ("invalid-answer-pause", "Invalid answer_pause, use <seconds> or <from>,<to> with from not greater than to"),
+    ("answer-pause-out-of-range", "Values must be between 0 and 3600 (inclusive)."),
+    ("footer-too-long", "Maximum footer length is 40 symbols"),
+    ("current-fallback", "Current {kind} reply: {value}"),
+    ("invalid-fallback", "Invalid reply type. Allowed values are: error, too_long, unsupported_media, budget_exhausted"),
+    ("fallback-too-long", "Maximum reply length is 200 symbols"),
+    ("sandbox-on", "Sandbox mode on: your messages here are answered as if you were a customer.\n/try pause - also apply the answer pause\n/try stop - back to setup"),
+    ("sandbox-on-pause", "Sandbox mode on, with answer pause"),
+    ("sandbox-off", "Sandbox mode off"),
+    ("account-blocked", "Your account is blocked by the service operator\ncontact {contact}"),
+    ("admin-help", "/businesses - list connected businesses\n/usage <user_id> - token usage of a business for 30 days\n/limit <user_id> <tokens> - set the token limit of a business, [none] to remove it\n/block <user_id> - block an account, /unblock <user_id> to restore it\n/broadcast <text> - send an announcement to all owners\n/errors - recent errors\n/plans - list plans\n/edit_plan <plan> <models|tokens|cost|history|features|trial_days|price|period_days> <value> - create or change a plan, [none] removes the restriction\n/assign_plan <user_id> <plan> [days] - assign a plan, [none] to remove it, the owner can then answer only with their own key\n/payments [user_id] - latest payments"),
+    ("no-businesses", "No connected businesses"),
+    ("business-line", "{id} {name} @{username}: {status}, replies {can_reply}, spent {spent}, limit {limit}"),
+    ("usage-summary", "Spent {spent} of {limit} tokens"),
+    ("usage-line", "{model} at {provider} with {key}: {answers} answers, {tokens} tokens"),
+    ("key-shared", "shared key"),
+    ("key-own", "own key"),
+    ("usage-empty", "No answers in the last {days} days"),
+    ("invalid-user-id", "Invalid user id"),
+    ("user-not-found", "User not found"),
+    ("empty-broadcast", "Broadcast text is empty"),
+    ("broadcast-started", "Sending the announcement to {count} owners"),
+    ("broadcast-finished", "Announcement sent: {sent}, failed: {failed}"),
+    ("no-errors", "No errors since start"),
+    ("plan-description", "{name}: models {models}, {tokens} tokens and {cost} per month on the shared key, history {history} symbols, features: {features}, price: {price}"),
+    ("monthly-usage", "Used this month with the shared key: {tokens} tokens, {cost}"),
+    ("plan-expires", "Valid until {date}"),
+    ("plan-expired", "Expired on {date}, answers are paused"),
+    ("no-plan", "No plan: answers use only your own API key, set it with /api_key or see /buy"),
+    ("plan-not-found", "Plan not found"),
+    ("invalid-plan-field", "Invalid field. Allowed values are: models, tokens, cost, history, features, trial_days, price, period_days"),
+    ("invalid-plan-value", "Invalid value"),
+    ("invalid-feature", "Invalid feature. Allowed values are: voice, rag"),
+    ("model-not-in-plan", "This model is not included in your plan, see /plan"),
+    ("history-length-over-plan", "This history length exceeds your plan, see /plan"),
+    ("plan-price", "{price} ⭐ for {days} days"),
+    ("no-payments", "No payments"),
+    ("payment-line", "{date} {user_id}: {amount} ⭐, {product}, {charge_id}"),
+    ("buy-plan", "/buy {name} - {price}\n{description}"),
+    ("buy-tokens", "/buy tokens - {tokens} extra tokens for {price} ⭐, used on the shared key when the monthly quota is over"),
+    ("nothing-to-buy", "Nothing is on sale now\ncontact {contact}"),
+    ("unknown-product", "Unknown plan, send /buy to see the available ones"),
+    ("invoice-plan-title", "Plan {name}"),
+    ("invoice-plan-description", "Plan {name} for {days} days"),
+    ("invoice-tokens-title", "{tokens} tokens"),
+    ("invoice-tokens-description", "{tokens} extra tokens, used on the shared key when the monthly quota of your plan is over"),
+    ("payment-unavailable", "This purchase is no longer available"),
+    ("plan-purchased", "Thank you! Plan {name} is active until {date}"),
+    ("tokens-purchased", "Thank you! {tokens} tokens were added to your balance"),
+    ("payment-failed", "Payment received, but it could not be applied\ncontact {contact}"),
+];
+
+const RU: &[(&str, &str)] = &[
+    ("connection-created", "подключено\nсправка: /help"),
+    ("connection-created-shared-key", "подключено\nответы уже работают на общем ключе сервиса, при желании задайте свой через /api_key\nсправка: /help"),
+    ("connection-cannot-reply", "подключено, но боту не разрешено отвечать\nоткройте Настройки → Telegram для бизнеса → Чат-боты и включите для бота \"Отвечать на сообщения\", затем /help для справки"),
+    ("connection-disabled", "отключено\nнастройки сохранены до повторного подключения, чтобы удалить их, отправьте /forget_me"),
+    ("forget-me-confirm", "Все ваши настройки, API-ключ и история расходов будут удалены. Отправьте /forget_me confirm для подтверждения"),
+    ("forgotten", "Ваши данные удалены"),
+    ("only-for-business", "только для бизнес-аккаунтов\nконтакт {contact}"),
+    ("only-text", "Только текст"),
+    ("too-long-message", "слишком длинное сообщение"),
+    ("no-answer", "Не знаю, что ответить"),
+    ("option-updated", "Настройка обновлена"),
+    ("unknown-command", "Неизвестная команда"),
+    ("help-failed", "не удалось получить справку"),
+    ("error-database", "Не удалось обратиться к хранилищу, попробуйте позже"),
+    ("error-redis", "Не удалось обратиться к истории переписки, попробуйте позже"),
+    ("error-telegram", "Запрос к Telegram не удался, попробуйте позже"),
+    ("error-provider", "Запрос к AI-провайдеру не удался, попробуйте позже"),
+    ("error-conflict", "Настройки были изменены одновременно, повторите команду"),
+    ("error-internal", "Что-то пошло не так, попробуйте позже"),
+    ("missing-api-key", "API-ключ не задан, используйте /api_key"),
+    ("current-api-key", "Текущий API-ключ: {value}"),
+    ("api-key-updated", "API-ключ сохранён: {value}"),
+    ("default-api-key", "общий ключ сервиса"),
+    ("current-history-timeout", "Текущее время хранения истории: {value} сек."),
+    ("current-history-length", "Текущая длина истории: {value} символов"),
+    ("current-answer-pause", "Текущая пауза перед ответом: {value} сек."),
+    ("current-answer-pause-range", "Текущая пауза перед ответом: от {from} до {to} сек."),
+    ("current-footer", "Текущая подпись: {value}"),
+    ("current-model", "Текущая модель: {value}"),
+    ("current-prompt", "Текущий промпт: {value}"),
+    ("current-max-message-length", "Текущая максимальная длина сообщения: {value}"),
+    ("current-max-total-tokens-spent", "Текущий лимит потраченных токенов: {value}"),
+    ("current-max-tokens", "Текущий лимит токенов на ответ: {value}"),
+    ("current-language", "Текущий язык: {value}"),
+    ("current-reply-threading", "Ответ цитатой: {value}"),
+    ("max-prompt-size", "Максимальный размер промпта — 4000 символов"),
+    ("invalid-history-timeout", "Некорректное значение history_timeout"),
+    ("invalid-history-length", "Некорректное значение history_length"),
+    ("invalid-length", "Некорректная длина"),
+    ("invalid-token-amount", "Некорректное количество токенов"),
+    ("invalid-language", "Некорректный язык. Допустимые значения: en, ru"),
+    ("invalid-reply-threading", "Некорректное значение. Допустимые значения: on, off"),
+    ("invalid-api-key", "Некорректный API-ключ"),
+    ("api-key-check-failed", "Не удалось проверить API-ключ"),
+    ("invalid-model", "Некорректная модель. Допустимые значения: gpt-3.5-turbo, gpt-4-turbo, gpt-4o, gpt-4o-mini"),
+    ("current-fallback-models", "Текущие резервные модели: {value}"),
+    ("invalid-fallback-model", "Некорректная резервная модель. Укажите допустимую модель или model@https://host/v1 для своей"),
+    ("fallback-url-not-allowed", "Этот адрес своей модели не разрешён оператором"),
+    ("too-many-fallback-models", "Максимум 3 резервные модели"),
+    ("current-lead-fields", "Текущие поля заявки: {value}"),
+    ("too-many-lead-fields", "Максимум 8 полей заявки"),
+    ("lead-field-too-long", "Название поля заявки должно быть не длиннее 50 символов"),
+    ("no-leads", "Заявок пока нет"),
+    ("lead-line", "{date} клиент {customer}: {fields}"),
+    ("lead-line-complete", "{date} клиент {customer} [заполнена]: {fields}"),
+    ("lead-completed", "Новая заявка от клиента {customer}:\n{fields}"),
+    ("prompt-too-long", "Промпт слишком длинный. Максимум — 4000 символов"),
+    ("max-message-length-too-long", "Слишком большая длина сообщения. Максимум — 4000"),
+    ("history-timeout-too-long", "Максимальная длительность — 3600 секунд"),
+    ("history-length-too-long", "Максимальный лимит — 10000 символов"),
+    ("invalid-answer-pause", "Некорректное значение answer_pause, укажите <секунды> или <от>,<до>, где от не больше до"),
+    ("answer-pause-out-of-range", "Значения должны быть от 0 до 3600 включительно."),
+    ("footer-too-long", "Максимальная длина подписи — 40 символов"),
+    ("current-fallback", "Текущий ответ {kind}: {value}"),
+    ("invalid-fallback", "Некорректный тип ответа. Допустимые значения: error, too_long, unsupported_media, budget_exhausted"),
+    ("fallback-too-long", "Максимальная длина ответа — 200 символов"),
+    ("sandbox-on", "Режим песочницы включён: здесь на ваши сообщения отвечают как клиенту.\n/try pause - с паузой перед ответом\n/try stop - вернуться к настройке"),
+    ("sandbox-on-pause", "Режим песочницы включён, с паузой перед ответом"),
+    ("sandbox-off", "Режим песочницы выключен"),
+    ("account-blocked", "Ваш аккаунт заблокирован оператором сервиса\nконтакт {contact}"),
+    ("admin-help", "/businesses - список подключённых бизнесов\n/usage <user_id> - расход токенов бизнеса за 30 дней\n/limit <user_id> <tokens> - задать лимит токенов бизнеса, [none] чтобы снять его\n/block <user_id> - заблокировать аккаунт, /unblock <user_id> чтобы восстановить\n/broadcast <text> - отправить объявление всем владельцам\n/errors - последние ошибки\n/plans - список тарифов\n/edit_plan <plan> <models|tokens|cost|history|features|trial_days|price|period_days> <value> - создать или изменить тариф, [none] снимает ограничение\n/assign_plan <user_id> <plan> [days] - назначить тариф, [none] чтобы снять его, тогда владелец отвечает только своим ключом\n/payments [user_id] - последние платежи"),
+    ("no-businesses", "Нет подключённых бизнесов"),
+    ("business-line", "{id} {name} @{username}: {status}, ответы {can_reply}, потрачено {spent}, лимит {limit}"),
+    ("usage-summary", "Потрачено {spent} из {limit} токенов"),
+    ("usage-line", "{model} в {provider}, {key}: {answers} ответов, {tokens} токенов"),
+    ("key-shared", "общий ключ"),
+    ("key-own", "свой ключ"),
+    ("usage-empty", "Нет ответов за последние {days} дн."),
+    ("invalid-user-id", "Некорректный id пользователя"),
+    ("user-not-found", "Пользователь не найден"),
+    ("empty-broadcast", "Текст объявления пуст"),
+    ("broadcast-started", "Объявление отправляется {count} владельцам"),
+    ("broadcast-finished", "Объявление отправлено: {sent}, с ошибкой: {failed}"),
+    ("no-errors", "Ошибок с момента запуска нет"),
+    ("plan-description", "{name}: модели {models}, {tokens} токенов и {cost} в месяц на общем ключе, история {history} символов, функции: {features}, цена: {price}"),
+    ("monthly-usage", "Использовано в этом месяце с общим ключом: {tokens} токенов, {cost}"),
+    ("plan-expires", "Действует до {date}"),
+    ("plan-expired", "Истёк {date}, ответы приостановлены"),
+    ("no-plan", "Без тарифа: ответы работают только на вашем API-ключе, задайте его через /api_key или см. /buy"),
+    ("plan-not-found", "Тариф не найден"),
+    ("invalid-plan-field", "Некорректное поле. Допустимые значения: models, tokens, cost, history, features, trial_days, price, period_days"),
+    ("invalid-plan-value", "Некорректное значение"),
+    ("invalid-feature", "Некорректная функция. Допустимые значения: voice, rag"),
+    ("model-not-in-plan", "Эта модель не входит в ваш тариф, см. /plan"),
+    ("history-length-over-plan", "Эта длина истории превышает ваш тариф, см. /plan"),
+    ("plan-price", "{price} ⭐ за {days} дн."),
+    ("no-payments", "Платежей нет"),
+    ("payment-line", "{date} {user_id}: {amount} ⭐, {product}, {charge_id}"),
+    ("buy-plan", "/buy {name} - {price}\n{description}"),
+    ("buy-tokens", "/buy tokens - {tokens} дополнительных токенов за {price} ⭐, расходуются на общем ключе после месячной квоты"),
+    ("nothing-to-buy", "Сейчас ничего не продаётся\nконтакт {contact}"),
+    ("unknown-product", "Неизвестный тариф, отправьте /buy, чтобы увидеть доступные"),
+    ("invoice-plan-title", "Тариф {name}"),
+    ("invoice-plan-description", "Тариф {name} на {days} дн."),
+    ("invoice-tokens-title", "{tokens} токенов"),
+    ("invoice-tokens-description", "{tokens} дополнительных токенов, расходуются на общем ключе после месячной квоты тарифа"),
+    ("payment-unavailable", "Эта покупка больше недоступна"),
+    ("plan-purchased", "Спасибо! Тариф {name} действует до {date}"),
+    ("tokens-purchased", "Спасибо! На баланс добавлено {tokens} токенов"),
+    ("payment-failed", "Оплата получена, но не применена\nконтакт {contact}"),
+];
@@ -0,0 +1,84 @@
+use chrono::Utc;
+use serde_json::{Map, Value};
+use sqlx::{Pool, Postgres};
+use crate::conversation::Message;
+use crate::db;
+use crate::db::LeadRow;
+use crate::dialogue::{self, ChatResponse};
+use crate::error::Result;
+use crate::i18n::{tf, Lang};
+use crate::queue::ScheduledReply;
+use crate::user::OpenaiConfig;
+
+/// Fills the lead of a customer from the conversation and notifies the owner once all fields are known.
+/// Returns the extraction response for usage accounting, `None` if the lead was already complete
+/// or has all the configured fields.
+pub async fn capture(
+    pool: &Pool<Postgres>,
+    config: &OpenaiConfig,
+    user_id: i64,
+    sender_id: &str,
+    messages: Vec<Message>,
+    lang: Lang,
+) -> Result<Option<ChatResponse>> {
+    let fields = config.get_lead_fields();
+    if db::load_lead(pool, user_id, sender_id).await?
+        .is_some_and(|lead| lead.completed_at.is_some() || is_filled(fields, &lead.fields))
+    {
+        return Ok(None);
+    }
+
+    let response = dialogue::extract_lead(config, fields, messages).await?;
+    let values: Map<String, Value> = match serde_json::from_str::<Map<String, Value>>(&response.message) {
+        Ok(arguments) => arguments.into_iter()
+            .filter(|(field, value)| fields.contains(field) && value.as_str().is_some_and(|value| !value.trim().is_empty()))
+            .collect(),
+        Err(e) => {
+            tracing::warn!(error = %e, "Invalid lead arguments");
+            return Ok(Some(response));
+        }
+    };
+    if values.is_empty() {
+        return Ok(Some(response));
+    }
+
+    let lead = db::save_lead(pool, user_id, sender_id, &Value::Object(values)).await?;
+    if is_filled(fields, &lead) && db::complete_lead(pool, user_id, sender_id).await? {
+        db::insert_scheduled_reply(pool, &ScheduledReply {
+            chat_id: user_id,
+            business_id: None,
+            message_id: None,
+            reply_to_message_id: None,
+            text: tf(lang, "lead-completed", &[
+                ("customer", sender_id),
+                ("fields", &describe_fields(fields, &lead)),
+            ]),
+            footer: None,
+            send_at: Utc::now(),
+        }).await?;
+    }
+    Ok(Some(response))
+}
+
+fn is_filled(fields: &[String], lead: &Value) -> bool {
+    fields.iter().all(|field| lead.get(field).is_some_and(Value::is_string))
+}
+
+/// One lead per line, for the owner's `/leads` command.
+pub fn describe(lead: &LeadRow, fields: &[String], lang: Lang) -> String {
+    tf(lang, if lead.completed_at.is_some() { "lead-line-complete" } else { "lead-line" }, &[
+        ("date", &lead.updated_at.format("%Y-%m-%d %H:%M").to_string()),
+        ("customer", &lead.sender_id),
+        ("fields", &describe_fields(fields, &lead.fields).replace('\n', ", ")),
+    ])
+}
+
+/// Configured fields first, then values of fields the owner removed since.
+fn describe_fields(fields: &[String], lead: &Value) -> String {
+    let Some(values) = lead.as_object() else { return String::new() };
+    fields.iter()
+        .chain(values.keys().filter(|field| !fields.contains(field)))
+        .filter_map(|field| values.get(field).and_then(Value::as_str).map(|value| format!("{field}: {value}")))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
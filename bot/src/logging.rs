@@ -0,0 +1,120 @@
+use std::collections::VecDeque;
+use std::env;
+use std::fmt::{self, Write as _};
+use std::io::{self, IsTerminal, Write};
+use std::sync::{Mutex, OnceLock};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use tracing::{Event, Level, Subscriber};
+use tracing::field::{Field, Visit};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Number of error events kept for the operator's `/errors` command.
+const RECENT_ERRORS: usize = 20;
+
+/// Installs the global subscriber. `RUST_LOG` sets the filter, `LOG_FORMAT=json`
+/// switches to one JSON object per line with the current span fields included.
+pub fn init() {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_ansi(io::stdout().is_terminal())
+        .with_writer(RedactingWriter);
+    match env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => builder.json().with_current_span(true).with_span_list(false).finish().with(RecentErrors).init(),
+        _ => builder.finish().with(RecentErrors).init(),
+    }
+}
+
+type ErrorLog = Mutex<VecDeque<(DateTime<Utc>, String)>>;
+
+fn recent_errors_log() -> &'static ErrorLog {
+    static ERRORS: OnceLock<ErrorLog> = OnceLock::new();
+    ERRORS.get_or_init(|| Mutex::new(VecDeque::with_capacity(RECENT_ERRORS)))
+}
+
+/// Last logged errors since start, newest first.
+pub fn recent_errors() -> Vec<(DateTime<Utc>, String)> {
+    recent_errors_log().lock().unwrap().iter().rev().cloned().collect()
+}
+
+/// Keeps the last error events in memory, already redacted.
+struct RecentErrors;
+
+impl<S: Subscriber> Layer<S> for RecentErrors {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        let mut text = String::new();
+        event.record(&mut EventText(&mut text));
+        let mut errors = recent_errors_log().lock().unwrap();
+        if errors.len() == RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back((Utc::now(), redact(&text)));
+    }
+}
+
+/// Renders an event as its message followed by `name=value` fields.
+struct EventText<'a>(&'a mut String);
+
+impl Visit for EventText<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = match field.name() {
+            "message" => write!(self.0, "{value:?}"),
+            name => write!(self.0, " {name}={value:?}"),
+        };
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let _ = match field.name() {
+            "message" => write!(self.0, "{value}"),
+            name => write!(self.0, " {name}={value}"),
+        };
+    }
+}
+
+/// Message text for logs: customer and model messages are hidden unless `LOG_MESSAGE_CONTENTS` is set.
+pub fn content(text: &str) -> String {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    if *ENABLED.get_or_init(|| env::var("LOG_MESSAGE_CONTENTS").is_ok_and(|value| !value.is_empty())) {
+        text.to_string()
+    } else {
+        format!("<redacted {} chars>", text.chars().count())
+    }
+}
+
+/// Masks provider API keys, bearer tokens and Telegram bot tokens that end up in error messages.
+fn redact(line: &str) -> String {
+    static PATTERNS: OnceLock<Regex> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        Regex::new(r"sk-[A-Za-z0-9_-]{8,}|(?i:bearer)\s+[A-Za-z0-9._~+/=-]{8,}|\d{6,}:[A-Za-z0-9_-]{30,}")
+            .expect("Invalid redaction pattern")
+    }).replace_all(line, "[REDACTED]").into_owned()
+}
+
+struct RedactingWriter;
+
+impl<'a> MakeWriter<'a> for RedactingWriter {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter
+    }
+}
+
+impl Write for RedactingWriter {
+    /// The formatter writes each event with a single call, so a secret is never split between buffers.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = redact(&String::from_utf8_lossy(buf));
+        io::stdout().lock().write_all(line.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
@@ -2,6 +2,24 @@ mod dialogue;
 mod user;
 mod db;
 mod conversation;
+mod i18n;
+mod webhook;
+mod shutdown;
+mod queue;
+mod dispatcher;
+mod error;
+mod secrets;
+mod retention;
+mod format;
+mod metrics;
+mod health;
+mod status;
+mod logging;
+mod admin;
+mod plan;
+mod payments;
+mod config;
+mod lead;
 
 use rand::Rng;
 use std::env;
@@ -10,157 +28,422 @@ use sqlx::{Pool, Postgres};
 
 use tgbot::{
     api::Client,
-    handler::{LongPoll, UpdateHandler},
+    handler::UpdateHandler,
     types::{SendMessage, Update},
 };
-use tgbot::types::{Chat, ChatAction, SendChatAction, UpdateType};
-use tokio::time::{sleep, Duration};
-use crate::user::{Openai, User};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use chrono::Utc;
+use tgbot::types::{AnswerPreCheckoutQuery, Chat, DeleteMessage, DeleteWebhook, MessageData, UpdateType};
+use tokio::sync::watch;
+use tokio::time::Duration;
+use tracing::{field, Instrument, Span};
+use crate::conversation::{ConversationManager, Message};
+use crate::db::SettingsRow;
+use crate::dialogue::ChatResponse;
+use crate::dispatcher::{Dispatcher, LlmLimiter};
+use crate::error::{Error, Result};
+use crate::i18n::{t, tf, Lang};
+use crate::queue::ScheduledReply;
+use crate::shutdown::{PendingReply, Tracker};
+use crate::user::{Connection, Fallback, OpenaiConfig, Sandbox, User};
 
 const MAX_PROMPT_SIZE: usize = 4_000;
+const MAX_LISTED_LEADS: i64 = 20;
+/// Sender id prefix of the owner's own messages in sandbox mode.
+const SANDBOX_SENDER: &str = "sandbox:";
+/// Commands whose message is deleted after processing, so the secret does not stay in the chat.
+const SECRET_COMMANDS: [&str; 1] = ["/api_key"];
 
 struct Handler {
     client: Client,
     pool: Pool<Postgres>,
+    tracker: Tracker,
+    llm_limiter: Arc<LlmLimiter>,
 }
 
 impl UpdateHandler for Handler {
     async fn handle(&self, update: Update) {
+        let kind = metrics::update_type(&update);
+        metrics::get().updates.with_label_values(&[kind]).inc();
+        let span = tracing::info_span!(
+            "update",
+            update_id = update.id,
+            kind,
+            chat_id = update.get_chat_id().map(i64::from),
+            user_id = field::Empty,
+        );
+        async {
+            if let Err(e) = self.process(update).await {
+                tracing::error!(error = %e, "Failed handle update");
+            }
+        }.instrument(span).await
+    }
+}
+
+impl Handler {
+    async fn process(&self, update: Update) -> Result<()> {
         let method = match update.update_type {
             UpdateType::BusinessConnection(connection) => {
-                Some(SendMessage::new(connection.user_chat_id, match connection.is_enabled {
-                    true => {
-                        db::insert_or_update_user(&self.pool, connection.user_chat_id, &connection.id).await.unwrap();
-                        "created\nnow /help for info"
+                let lang = Lang::from_user(Some(&connection.user));
+                let result = match connection.is_enabled {
+                    true => match db::insert_or_update_user(&self.pool, &Connection::from(&connection)).await {
+                        Ok(false) => Ok("account-blocked"),
+                        Ok(true) if !connection.can_reply => Ok("connection-cannot-reply"),
+                        Ok(true) => db::load_user_from_chat_id(&self.pool, connection.user_chat_id).await
+                            .map(|user| match user.is_some_and(|user| user.can_use_shared_key()) {
+                                true => "connection-created-shared-key",
+                                false => "connection-created",
+                            }),
+                        Err(e) => Err(e),
+                    },
+                    false => db::disable_user_by_id(&self.pool, connection.user_chat_id).await
+                        .map(|()| "connection-disabled"),
+                };
+                if !connection.is_enabled || !connection.can_reply {
+                    if let Err(e) = db::delete_scheduled_replies_by_business_id(&self.pool, &connection.id).await {
+                        tracing::error!(error = %e, "Failed drop scheduled replies of connection {}", connection.id);
                     }
-                    false => {
-                        db::delete_user_by_id(&self.pool, connection.user_chat_id).await.unwrap();
-                        "deleted"
+                }
+                Some(SendMessage::new(connection.user_chat_id, match result {
+                    Ok(key) => tf(lang, key, &[("contact", &get_contact())]),
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed update business connection {}", connection.id);
+                        e.user_message(lang)
                     }
                 }))
             }
             UpdateType::BusinessMessage(message) => {
-                let business_id = message.business_connection_id.clone().unwrap();
-                match db::load_user_from_business_id(&self.pool, &business_id).await {
-                    Ok(user) => {
-                        let sender_id = message.sender.get_user_id();
-                        if let Some(sender_id) = sender_id {
-                            let sender_id: i64 = sender_id.into();
-                            if sender_id == user.get_id() {
-                                return;
-                            }
-                        } else { return; }
-                        Some(
-                            SendMessage::new(
-                                message.chat.get_id(),
-                                {
-                                    let response = match message.get_text() {
-                                        Some(text) => match get_answer(
-                                            &self.pool, &user, &sender_id.unwrap().to_string(), &text.clone().data,
-                                            || async {
-                                                let _ = self.client.execute(
-                                                    SendChatAction::new(
-                                                        message.chat.get_id(),
-                                                        ChatAction::Typing,
-                                                    ).with_business_connection_id(
-                                                        &business_id
-                                                    )
-                                                ).await;
-                                            }
-                                        ).await {
-                                            Ok(Some(message)) => message,
-                                            Ok(None) => { return; }
-                                            Err(e) => e
-                                        },
-                                        None => "Only text".to_string()
-                                    };
-                                    match user.get_config().get_footer() {
-                                        Some(footer) => format!("{}\n\n{}", response, footer),
-                                        None => response,
-                                    }
-                                }
-                            ).with_business_connection_id(business_id)
-                        )
-                    }
-                    Err(_) => {
-                        log::error!("Not found user with business_id={}", business_id);
+                let business_id = message.business_connection_id.clone()
+                    .ok_or_else(|| Error::internal("Business message without business_connection_id"))?;
+                let Some(user) = db::load_user_from_business_id(&self.pool, &business_id).await? else {
+                    tracing::error!("Not found user with business_id={}", business_id);
+                    return Ok(());
+                };
+                Span::current().record("user_id", user.get_id());
+                if !user.can_reply() {
+                    tracing::info!("Skipped message for user {} without reply rights", user.get_id());
+                    return Ok(());
+                }
+                let Some(sender_id) = message.sender.get_user_id() else { return Ok(()); };
+                if i64::from(sender_id) == user.get_id() {
+                    return Ok(());
+                }
+                let lang = user.get_config().get_language()
+                    .unwrap_or_else(|| Lang::from_user(message.sender.get_user()));
+                match message.get_text() {
+                    Some(text) => {
+                        self.reply(&user, PendingReply {
+                            user_id: user.get_id(),
+                            chat_id: message.chat.get_id().into(),
+                            business_id: Some(business_id),
+                            sender_id: sender_id.to_string(),
+                            message_id: Some(message.id),
+                            message: text.data.clone(),
+                            lang,
+                            send_at: Utc::now() + random_answer_pause(&user.get_config()),
+                        }).await;
                         None
                     }
+                    None => match user.get_config().get_fallback(Fallback::UnsupportedMedia, lang) {
+                        Some(response) => Some(
+                            SendMessage::new(message.chat.get_id(), with_footer(&user, response))
+                                .with_business_connection_id(business_id)
+                        ),
+                        None => { return Ok(()); }
+                    }
+                }
+            }
+            UpdateType::EditedBusinessMessage(message) => {
+                let business_id = message.business_connection_id.clone()
+                    .ok_or_else(|| Error::internal("Edited business message without business_connection_id"))?;
+                let Some(user) = db::load_user_from_business_id(&self.pool, &business_id).await? else {
+                    return Ok(());
+                };
+                Span::current().record("user_id", user.get_id());
+                let Some(sender_id) = message.sender.get_user_id() else { return Ok(()); };
+                let Some(text) = message.get_text() else { return Ok(()); };
+                if i64::from(sender_id) == user.get_id() {
+                    return Ok(());
+                }
+                let chat_id: i64 = message.chat.get_id().into();
+                let manager = user.get_config().get_manager(user.get_id())?;
+                // An answer still waiting in the queue is generated again for the new text
+                match db::take_scheduled_reply(&self.pool, &business_id, chat_id, message.id).await? {
+                    Some(send_at) => {
+                        manager.remove_message(&sender_id.to_string(), message.id, true).await?;
+                        let lang = user.get_config().get_language()
+                            .unwrap_or_else(|| Lang::from_user(message.sender.get_user()));
+                        self.reply(&user, PendingReply {
+                            user_id: user.get_id(),
+                            chat_id,
+                            business_id: Some(business_id),
+                            sender_id: sender_id.to_string(),
+                            message_id: Some(message.id),
+                            message: text.data.clone(),
+                            lang,
+                            send_at,
+                        }).await;
+                    }
+                    None => {
+                        manager.edit_message(&sender_id.to_string(), message.id, &text.data).await?;
+                    }
+                }
+                None
+            }
+            UpdateType::DeletedBusinessMessages(deleted) => {
+                let business_id = deleted.business_connection_id;
+                let Some(user) = db::load_user_from_business_id(&self.pool, &business_id).await? else {
+                    return Ok(());
+                };
+                Span::current().record("user_id", user.get_id());
+                let chat_id: i64 = deleted.chat.get_id().into();
+                let manager = user.get_config().get_manager(user.get_id())?;
+                for message_id in deleted.message_ids {
+                    let unsent = db::take_scheduled_reply(&self.pool, &business_id, chat_id, message_id).await?.is_some();
+                    manager.remove_message(&chat_id.to_string(), message_id, unsent).await?;
                 }
+                None
+            }
+            UpdateType::PreCheckoutQuery(query) => {
+                let lang = Lang::from_user(Some(&query.from));
+                let answer = match payments::check(&self.pool, &query, lang).await {
+                    Ok(answer) => answer,
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed check pre-checkout query");
+                        AnswerPreCheckoutQuery::error(query.id.clone(), e.user_message(lang))
+                    }
+                };
+                self.client.execute(answer).await?;
+                None
             }
             UpdateType::Message(message) => {
                 let chat_id = match &message.chat {
                     Chat::Private(chat) => chat.id,
-                    _ => return,
+                    _ => return Ok(()),
                 };
-                match db::load_user_from_chat_id(&self.pool, chat_id.into()).await {
-                    Ok(mut user) => Some(SendMessage::new(chat_id, setup(
-                        &self.pool,
-                        &mut user,
-                        match message.get_text() {
+                // Before anything else, so the secret doesn't stay in the chat of blocked or unknown senders
+                if message.get_text().is_some_and(|text| is_secret_command(&text.data)) {
+                    if let Err(e) = self.client.execute(DeleteMessage::new(chat_id, message.id)).await {
+                        tracing::warn!(error = %e, "Failed delete secret command in chat {}", chat_id);
+                    }
+                }
+                if let MessageData::SuccessfulPayment(payment) = &message.data {
+                    let lang = Lang::from_user(message.sender.get_user());
+                    let response = match payments::complete(&self.pool, chat_id.into(), payment, lang).await {
+                        Ok(Some(response)) => response,
+                        Ok(None) => return Ok(()),
+                        Err(e) => {
+                            tracing::error!(
+                                error = %e, charge_id = %payment.telegram_payment_charge_id, "Failed apply payment"
+                            );
+                            tf(lang, "payment-failed", &[("contact", &get_contact())])
+                        }
+                    };
+                    self.client.execute(SendMessage::new(chat_id, response)).await?;
+                    return Ok(());
+                }
+                if let Some(text) = message.get_text().filter(|text| admin::is_command(&text.data)) {
+                    if admin::is_admin(chat_id.into()) {
+                        let lang = Lang::from_user(message.sender.get_user());
+                        let response = match admin::execute(&self.pool, &self.client, chat_id.into(), &text.data, lang).await {
+                            Ok(response) => response,
+                            Err(e) => {
+                                if !matches!(e, Error::Localized(_)) {
+                                    tracing::error!(error = %e, "Failed admin command");
+                                }
+                                e.user_message(lang)
+                            }
+                        };
+                        self.send_text(chat_id.into(), &response).await?;
+                        return Ok(());
+                    }
+                }
+                match db::load_user_from_chat_id(&self.pool, chat_id.into()).await? {
+                    Some(mut user) => {
+                        Span::current().record("user_id", user.get_id());
+                        let lang = user.get_config().get_language()
+                            .unwrap_or_else(|| Lang::from_user(message.sender.get_user()));
+                        if user.is_blocked() {
+                            let response = tf(lang, "account-blocked", &[("contact", &get_contact())]);
+                            self.client.execute(SendMessage::new(chat_id, response)).await?;
+                            return Ok(());
+                        }
+                        let text = match message.get_text() {
                             Some(text) => text.clone().data,
-                            None => "Only text".to_string()
-                        }).await.unwrap_or_else(|e| e.to_string()),
-                    )),
-                    Err(_) => {
-                        let contact = env::var("CONTACT").unwrap_or("@DigitalScyther".to_string());
-                        Some(SendMessage::new(chat_id, format!("only for business\ncontact {contact}")))
+                            None => {
+                                let response = match user.get_config().get_sandbox() {
+                                    Some(_) => match user.get_config().get_fallback(Fallback::UnsupportedMedia, lang) {
+                                        Some(message) => with_footer(&user, message),
+                                        None => { return Ok(()); }
+                                    },
+                                    None => t(lang, "only-text"),
+                                };
+                                self.client.execute(SendMessage::new(chat_id, response)).await?;
+                                return Ok(());
+                            }
+                        };
+                        if let Some(sandbox) = user.get_config().get_sandbox() {
+                            if !text.starts_with('/') {
+                                let delay = match sandbox.get_pause() {
+                                    true => random_answer_pause(&user.get_config()),
+                                    false => Duration::ZERO,
+                                };
+                                self.reply(&user, PendingReply {
+                                    user_id: user.get_id(),
+                                    chat_id: chat_id.into(),
+                                    business_id: None,
+                                    sender_id: format!("{SANDBOX_SENDER}{}", user.get_id()),
+                                    message_id: Some(message.id),
+                                    message: text,
+                                    lang,
+                                    send_at: Utc::now() + delay,
+                                }).await;
+                                return Ok(());
+                            }
+                        }
+                        if let Some(product) = payments::Product::from_command(&text) {
+                            match payments::invoice(&self.pool, chat_id.into(), &product, lang).await {
+                                Ok(invoice) => { self.client.execute(invoice).await?; }
+                                Err(e) => { self.client.execute(SendMessage::new(chat_id, e.user_message(lang))).await?; }
+                            }
+                            return Ok(());
+                        }
+                        let response = match setup(&self.pool, &mut user, text, lang).await {
+                            Ok(response) => response,
+                            Err(e) => {
+                                if !matches!(e, Error::Localized(_) | Error::Conflict) {
+                                    tracing::error!(error = %e, "Failed setup for user {}", user.get_id());
+                                }
+                                e.user_message(lang)
+                            }
+                        };
+                        self.send_text(chat_id.into(), &response).await?;
+                        None
+                    }
+                    None => {
+                        let lang = Lang::from_user(message.sender.get_user());
+                        Some(SendMessage::new(chat_id, tf(lang, "only-for-business", &[("contact", &get_contact())])))
                     }
                 }
             }
             _ => {
-                log::info!("Skipped unexpected type message");
+                tracing::info!("Skipped unexpected type message");
                 None
             }
         };
 
         if let Some(method) = method {
-            self.client.execute(method).await.unwrap();
+            self.client.execute(method).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a plain text reply, split when it is over the Telegram message limit.
+    async fn send_text(&self, chat_id: i64, text: &str) -> Result<()> {
+        for part in format::split(text) {
+            self.client.execute(SendMessage::new(chat_id, part)).await?;
+        }
+        Ok(())
+    }
+
+    /// Generates an answer and puts it into the queue for `send_at`.
+    /// Tracked until queued, so shutdown can wait for it or persist it.
+    async fn reply(&self, user: &User, reply: PendingReply) {
+        let _guard = self.tracker.track(reply.clone());
+        tracing::debug!(text = %logging::content(&reply.message), "Generating answer");
+        let response = match get_answer(
+            &self.pool, &self.llm_limiter, user, &reply.sender_id, &reply.message, reply.message_id, reply.lang,
+        ).await {
+            Ok(Some(message)) => message,
+            Ok(None) => { return; }
+            Err(e) => e
+        };
+
+        let scheduled = ScheduledReply {
+            chat_id: reply.chat_id,
+            business_id: reply.business_id,
+            message_id: reply.message_id,
+            reply_to_message_id: reply.message_id.filter(|_| user.get_config().get_reply_threading()),
+            text: response,
+            footer: user.get_config().get_footer(),
+            send_at: reply.send_at,
+        };
+        if let Err(e) = db::insert_scheduled_reply(&self.pool, &scheduled).await {
+            tracing::error!(error = %e, "Failed schedule reply to chat {}, sending now", scheduled.chat_id);
+            queue::send(&self.client, scheduled).await;
+        }
+    }
+
+    /// Sends replies persisted by a previous shutdown.
+    async fn resume(&self, reply: PendingReply) {
+        match db::load_user_from_chat_id(&self.pool, reply.user_id).await {
+            Ok(Some(user)) => {
+                let span = tracing::info_span!("resume", user_id = reply.user_id, chat_id = reply.chat_id);
+                self.reply(&user, reply).instrument(span).await
+            }
+            Ok(None) => tracing::warn!("Dropped pending reply of removed user {}", reply.user_id),
+            Err(e) => tracing::error!(error = %e, "Failed load user {} for pending reply", reply.user_id),
         }
     }
 }
 
-async fn setup(pool: &Pool<Postgres>, user: &mut User, command: String) -> Result<String, String> {
+async fn setup(pool: &Pool<Postgres>, user: &mut User, command: String, lang: Lang) -> Result<String> {
     let mut config = user.get_config();
     let parts: Vec<&str> = command.split_whitespace().collect();
 
     let response = match parts.as_slice() {
+        ["/api_key", "[default]"] => {
+            config.reset_api_key();
+            t(lang, "option-updated")
+        }
         ["/api_key", new_api_key] => {
             config.set_api_key(new_api_key.to_string()).await?;
-            "Option updated".to_string()
+            tf(lang, "api-key-updated", &[("value", &config.get_api_key().unwrap_or_default())])
         }
         ["/api_key"] => {
-            format!("Current API key: {:?}", config.get_api_key().unwrap_or("---".to_string()))
+            let value = match config.get_api_key() {
+                Some(value) => format!("{value:?}"),
+                None if user.can_use_shared_key() => t(lang, "default-api-key"),
+                None => "---".to_string(),
+            };
+            tf(lang, "current-api-key", &[("value", &value)])
         }
         ["/history_timeout", new_cache_duration] => {
-            let cache_duration: i64 = new_cache_duration.parse().map_err(|_| "Invalid history_timeout")?;
+            let cache_duration: i64 = new_cache_duration.parse().map_err(|_| "invalid-history-timeout")?;
             config.set_cache_duration(cache_duration)?;
-            "Option updated".to_string()
+            t(lang, "option-updated")
         }
         ["/history_timeout"] => {
-            format!("Current history timeout: {:?} seconds", config.get_cache_duration())
+            tf(lang, "current-history-timeout", &[("value", &config.get_cache_duration().to_string())])
         }
         ["/history_length", new_char_limit] => {
-            let char_limit: usize = new_char_limit.parse().map_err(|_| "Invalid history_length")?;
+            let char_limit: usize = new_char_limit.parse().map_err(|_| "invalid-history-length")?;
+            if let Some(plan) = user.get_plan() {
+                plan.check_history_length(char_limit)?;
+            }
             config.set_char_limit(char_limit)?;
-            "Option updated".to_string()
+            t(lang, "option-updated")
         }
         ["/history_length"] => {
-            format!("Current history length: {:?} symbols", config.get_char_limit())
+            tf(lang, "current-history-length", &[("value", &config.get_char_limit().to_string())])
         }
         ["/answer_pause", new_answer_pause] => {
             config.set_answer_pause(new_answer_pause)?;
-            "Option updated".to_string()
+            t(lang, "option-updated")
         }
         ["/answer_pause"] => {
             let answer_pause = config.get_answer_pause();
-            format!("Current answer pause: {}", match answer_pause.0 == answer_pause.1 {
-                true => format!("{} seconds", answer_pause.0),
-                false => format!("from {} to {} seconds", answer_pause.0, answer_pause.1),
-            })
+            match answer_pause.0 == answer_pause.1 {
+                true => tf(lang, "current-answer-pause", &[("value", &answer_pause.0.to_string())]),
+                false => tf(lang, "current-answer-pause-range", &[
+                    ("from", &answer_pause.0.to_string()),
+                    ("to", &answer_pause.1.to_string()),
+                ]),
+            }
         }
         ["/answer_footer"] => {
-            format!("Current footer: {:?}", config.get_footer().unwrap_or("---".to_string()))
+            tf(lang, "current-footer", &[("value", &format!("{:?}", config.get_footer().unwrap_or("---".to_string())))])
         }
         ["/answer_footer", ..] => {
             let new_answer_footer = command.replacen("/answer_footer ", "", 1);
@@ -168,131 +451,439 @@ async fn setup(pool: &Pool<Postgres>, user: &mut User, command: String) -> Resul
                 true => None,
                 false => Some(new_answer_footer.to_string())
             })?;
-            "Option updated".to_string()
+            t(lang, "option-updated")
         }
         ["/model", new_model] => {
+            if let Some(plan) = user.get_plan() {
+                plan.check_model(new_model)?;
+            }
             config.set_model(new_model.to_string())?;
-            "Option updated".to_string()
+            t(lang, "option-updated")
         }
         ["/model"] => {
-            format!("Current model: {:?}", config.get_model())
+            tf(lang, "current-model", &[("value", &format!("{:?}", config.get_model()))])
         }
         ["/prompt"] => {
-            format!("Current prompt: {:?}", config.get_prompt().unwrap_or("---"))
+            tf(lang, "current-prompt", &[("value", &format!("{:?}", config.get_prompt().unwrap_or("---")))])
         }
         ["/prompt", ..] => {
             let new_prompt = command.replacen("/prompt ", "", 1);
             match new_prompt.len() > MAX_PROMPT_SIZE {
-                true => "Max prompt size is 4.000 symbols".to_string(),
+                true => t(lang, "max-prompt-size"),
                 false => {
                     config.set_prompt(new_prompt)?;   // TODO set prompt to None
-                    "Option updated".to_string()
+                    t(lang, "option-updated")
                 }
             }
         }
         ["/max_message_length", new_length] => {
-            let length: i32 = new_length.parse().map_err(|_| "Invalid length")?;
+            let length: i32 = new_length.parse().map_err(|_| "invalid-length")?;
             config.set_max_message_length(length)?;
-            "Option updated".to_string()
+            t(lang, "option-updated")
         }
         ["/max_message_length"] => {
-            format!("Current max message length: {}", config.get_max_message_length())
+            tf(lang, "current-max-message-length", &[("value", &config.get_max_message_length().to_string())])
         }
         ["/max_total_tokens_spent", new_tokens] => {
-            let tokens: i64 = new_tokens.parse().map_err(|_| "Invalid token amount")?;
+            let tokens: i64 = new_tokens.parse().map_err(|_| "invalid-token-amount")?;
             config.set_max_total_tokens_spent(tokens);
-            "Option updated".to_string()
+            t(lang, "option-updated")
         }
         ["/max_total_tokens_spent"] => {
-            format!("Current max total tokens spent: {}", config.get_max_total_tokens_spent())
+            tf(lang, "current-max-total-tokens-spent", &[("value", &config.get_max_total_tokens_spent().to_string())])
         }
         ["/max_tokens", new_tokens] => {
-            let tokens: u16 = new_tokens.parse().map_err(|_| "Invalid token amount")?;
+            let tokens: u16 = new_tokens.parse().map_err(|_| "invalid-token-amount")?;
             config.set_max_tokens(tokens);
-            "Option updated".to_string()
+            t(lang, "option-updated")
         }
         ["/max_tokens"] => {
-            format!("Current max tokens: {}", config.get_max_tokens())
+            tf(lang, "current-max-tokens", &[("value", &config.get_max_tokens().to_string())])
+        }
+        ["/language", new_language] => {
+            let language = Lang::from_code(new_language).ok_or("invalid-language")?;
+            config.set_language(language);
+            t(language, "option-updated")
+        }
+        ["/language"] => {
+            tf(lang, "current-language", &[("value", lang.code())])
+        }
+        ["/reply_threading", value] => {
+            config.set_reply_threading(match *value {
+                "on" => true,
+                "off" => false,
+                _ => return Err("invalid-reply-threading".into()),
+            });
+            t(lang, "option-updated")
+        }
+        ["/reply_threading"] => {
+            tf(lang, "current-reply-threading", &[("value", if config.get_reply_threading() { "on" } else { "off" })])
+        }
+        ["/fallback"] => {
+            ["error", "too_long", "unsupported_media", "budget_exhausted"].iter()
+                .map(|name| describe_fallback(&config, name, lang))
+                .collect::<Result<Vec<_>>>()?
+                .join("\n")
+        }
+        ["/fallback", name] => describe_fallback(&config, name, lang)?,
+        ["/fallback", name, ..] => {
+            let kind = Fallback::from_name(name).ok_or("invalid-fallback")?;
+            let new_fallback = command.replacen("/fallback ", "", 1).replacen(name, "", 1).trim().to_string();
+            config.set_fallback(kind, match new_fallback.to_lowercase().as_str() {
+                "[default]" => None,
+                "[silent]" => Some(String::new()),
+                _ => Some(new_fallback),
+            })?;
+            t(lang, "option-updated")
+        }
+        ["/fallback_models"] => {
+            let fallback_models = config.get_fallback_models();
+            tf(lang, "current-fallback-models", &[("value", &match fallback_models.is_empty() {
+                true => "---".to_string(),
+                false => fallback_models.join(", "),
+            })])
+        }
+        ["/fallback_models", ..] => {
+            let new_fallback_models = command.replacen("/fallback_models", "", 1);
+            let fallback_models: Vec<String> = match new_fallback_models.trim().to_lowercase() == "[empty]" {
+                true => vec![],
+                false => new_fallback_models.split(',')
+                    .map(|value| value.trim().to_string())
+                    .filter(|value| !value.is_empty())
+                    .collect(),
+            };
+            if let Some(plan) = user.get_plan() {
+                // Self-hosted models don't cost the operator anything
+                for model in fallback_models.iter().filter(|model| !model.contains('@')) {
+                    plan.check_model(model)?;
+                }
+            }
+            config.set_fallback_models(fallback_models)?;
+            t(lang, "option-updated")
+        }
+        ["/lead_fields"] => {
+            let lead_fields = config.get_lead_fields();
+            tf(lang, "current-lead-fields", &[("value", &match lead_fields.is_empty() {
+                true => "---".to_string(),
+                false => lead_fields.join(", "),
+            })])
         }
-        ["/help"] => read_to_string("./files/help_text.txt").unwrap_or_else(|e| {
-            log::error!("Failed get help command text:\n{e:?}");
-            "failed get help".to_string()
+        ["/lead_fields", ..] => {
+            let new_lead_fields = command.replacen("/lead_fields", "", 1);
+            config.set_lead_fields(match new_lead_fields.trim().to_lowercase() == "[empty]" {
+                true => vec![],
+                false => new_lead_fields.split(',')
+                    .map(|value| value.trim().to_string())
+                    .filter(|value| !value.is_empty())
+                    .collect(),
+            })?;
+            t(lang, "option-updated")
+        }
+        ["/leads"] => {
+            let leads = db::load_leads(pool, user.get_id(), MAX_LISTED_LEADS).await?;
+            return Ok(match leads.is_empty() {
+                true => t(lang, "no-leads"),
+                false => leads.iter()
+                    .map(|lead| lead::describe(lead, config.get_lead_fields(), lang))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            });
+        }
+        ["/try"] => {
+            config.set_sandbox(Some(Sandbox::new(false)));
+            t(lang, "sandbox-on")
+        }
+        ["/try", "pause"] => {
+            config.set_sandbox(Some(Sandbox::new(true)));
+            t(lang, "sandbox-on-pause")
+        }
+        ["/try", "stop"] => {
+            config.set_sandbox(None);
+            t(lang, "sandbox-off")
+        }
+        ["/plan"] => {
+            let Some(subscription) = user.get_subscription() else {
+                return Ok(t(lang, "no-plan"));
+            };
+            let (tokens, cost) = db::load_monthly_usage(pool, user.get_id()).await?;
+            let mut lines = vec![
+                subscription.plan.describe(lang),
+                tf(lang, "monthly-usage", &[("tokens", &tokens.to_string()), ("cost", &format!("${cost:.2}"))]),
+            ];
+            if let Some(expires_at) = subscription.expires_at {
+                let key = if subscription.is_expired() { "plan-expired" } else { "plan-expires" };
+                lines.push(tf(lang, key, &[("date", &expires_at.format("%Y-%m-%d").to_string())]));
+            }
+            return Ok(lines.join("\n"));
+        }
+        ["/buy"] => {
+            let offers = payments::describe_offers(pool, lang).await?;
+            return Ok(match offers.is_empty() {
+                true => tf(lang, "nothing-to-buy", &[("contact", &get_contact())]),
+                false => offers.join("\n\n"),
+            });
+        }
+        ["/forget_me"] => return Ok(t(lang, "forget-me-confirm")),
+        ["/forget_me", "confirm"] => {
+            db::delete_user_by_id(pool, user.get_id()).await?;
+            ConversationManager::for_owner(user.get_id())?.clear().await?;
+            return Ok(t(lang, "forgotten"));
+        }
+        ["/help"] => read_to_string(lang.help_file()).unwrap_or_else(|e| {
+            tracing::error!(error = %e, "Failed get help command text");
+            t(lang, "help-failed")
         }),
-        _ => "Unknown command".to_string()
+        _ => t(lang, "unknown-command")
     };
 
-    let openai: Openai = Openai::default()
-        .with_config(config.clone())
-        .with_spent_tokens(user.get_openai_spent_tokens());
-
-    if let Err(e) = db::update_openai_by_id(
-        pool,
-        user.get_id(),
-        openai,
-    ).await {
-        let err_str: String = format!("Failed update user openai:\n{e:?}");
-        return Err(err_str);
+    // Saving bumps the version, so read-only commands must not fail concurrent edits with a conflict
+    if SettingsRow::try_from(&config)? != SettingsRow::try_from(&user.get_config())? {
+        db::update_settings(pool, user.get_id(), &config).await?;
     }
 
-    Ok(response.to_string())
+    Ok(response)
 }
 
-async fn get_answer<F, Fut>(
+fn describe_fallback(config: &OpenaiConfig, name: &str, lang: Lang) -> Result<String> {
+    let kind = Fallback::from_name(name).ok_or("invalid-fallback")?;
+    Ok(tf(lang, "current-fallback", &[
+        ("kind", name),
+        ("value", &match config.get_fallback(kind, lang) {
+            Some(text) => format!("{text:?}"),
+            None => "[silent]".to_string(),
+        }),
+    ]))
+}
+
+/// Operator contact shown to people who can't use the bot.
+fn get_contact() -> String {
+    env::var("CONTACT").unwrap_or("@DigitalScyther".to_string())
+}
+
+fn is_secret_command(text: &str) -> bool {
+    let mut parts = text.split_whitespace();
+    parts.next().is_some_and(|command| SECRET_COMMANDS.contains(&command)) && parts.next().is_some()
+}
+
+fn random_answer_pause(config: &OpenaiConfig) -> Duration {
+    let (from, to) = config.get_answer_pause();
+    // Settings saved before the range was validated may have it reversed
+    Duration::from_secs(rand::thread_rng().gen_range(from.min(to)..=from.max(to)) as u64)
+}
+
+fn with_footer(user: &User, response: String) -> String {
+    match user.get_config().get_footer() {
+        Some(footer) => format!("{}\n\n{}", response, footer),
+        None => response,
+    }
+}
+
+async fn get_answer(
     pool: &Pool<Postgres>,
+    llm_limiter: &LlmLimiter,
     user: &User,
     sender_id: &str,
     message: &str,
-    call_typing: F
-) -> Result<Option<String>, String>
-where
-    F: Fn() -> Fut,
-    Fut: std::future::Future<Output = ()>,
-{
-    if user.get_openai_spent_tokens() > user.get_config().get_max_total_tokens_spent() {
-        return Ok(None);    // TODO send notification to owner
-    }
+    message_id: Option<i64>,
+    lang: Lang,
+) -> std::result::Result<Option<String>, String> {
+    let config = get_plan_config(user);
+    let Some(from_balance) = check_budget(pool, user).await else {
+        // TODO send notification to owner
+        return config.get_fallback(Fallback::BudgetExhausted, lang).map_or(Ok(None), Err);
+    };
 
-    if message.len() > user.get_config().get_max_message_length() as usize {
-        return Err("too long message".to_string());
+    if message.len() > config.get_max_message_length() as usize {
+        return config.get_fallback(Fallback::TooLong, lang).map_or(Ok(None), Err);
     }
 
-    let config = user.get_config();
+    let manager = match config.get_manager(user.get_id()) {
+        Ok(manager) => manager,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed open history for user {}", user.get_id());
+            return config.get_fallback(Fallback::Error, lang).map_or(Ok(None), Err);
+        }
+    };
 
-    Ok(config.get_manager().await.process_message(
+    Ok(manager.process_message(
         sender_id,
         message,
+        message_id,
         |messages| async {
-            let (from, to) = config.get_answer_pause();
-            let random_seconds = rand::thread_rng().gen_range(from..=to) as u64;
-            let duration = Duration::from_secs(random_seconds);
-            sleep(duration).await;
-            call_typing().await;
+            let _permit = llm_limiter.acquire(user.get_id()).await;
+            let lead_messages = (!config.get_lead_fields().is_empty() && !sender_id.starts_with(SANDBOX_SENDER))
+                .then(|| messages.clone());
             match dialogue::get_response(&config, messages).await {
                 Ok(response) => {
-                    if let Err(e) = db::add_spends(pool, user.get_id(), response.tokens_spent as i32).await {
-                        log::error!("Failed update tokens spent:{e:?}");
+                    record_usage(pool, user.get_id(), &response, from_balance).await;
+                    if let Some(mut lead_messages) = lead_messages {
+                        lead_messages.push(Message { role: "assistant".to_string(), content: response.message.clone(), message_id: None });
+                        tokio::spawn(capture_lead(
+                            pool.clone(), user.get_id(), sender_id.to_string(), lead_messages,
+                            config.get_language().unwrap_or(lang),
+                        ));
                     }
-                    return Ok(Some(response.message));
+                    tracing::debug!(answer = %logging::content(&response.message), "Generated answer");
+                    Ok(Some(response.message))
                 }
                 Err(err) => {
-                    log::error!("Failed get at response:\n{err:?}");
-                    Err("I don't know what to answer".to_string())
+                    tracing::error!(error = %err, "Failed get at response for user {}", user.get_id());
+                    config.get_fallback(Fallback::Error, lang).map_or(Ok(None), Err)
                 }
             }
         },
-    ).await.unwrap_or_else(|e| Some(e)))
+    ).await.unwrap_or_else(Some))
+}
+
+/// Settings of the owner restricted to the models and history length of their plan.
+fn get_plan_config(user: &User) -> OpenaiConfig {
+    let mut config = user.get_config();
+    if let Some(subscription) = user.get_subscription() {
+        config.restrict_to(&subscription.plan);
+    }
+    config
+}
+
+/// Whether the owner can pay for another model call, `Some(true)` if it is charged to the purchased
+/// token balance because the plan quota is over.
+async fn check_budget(pool: &Pool<Postgres>, user: &User) -> Option<bool> {
+    if user.get_openai_spent_tokens() > user.get_token_limit() {
+        return None;
+    }
+    let Some(subscription) = user.get_subscription() else {
+        // Without a plan nothing meters the shared key, so only the owner's own key is used
+        return (!user.get_config().uses_shared_key()).then_some(false);
+    };
+    if subscription.is_expired() {
+        return None;
+    }
+    // Quotas cover only the shared key, owners with their own key pay the provider themselves.
+    // Models and history length are restricted either way.
+    if subscription.plan.has_quota() && user.get_config().uses_shared_key() {
+        match db::load_monthly_usage(pool, user.get_id()).await {
+            Ok((tokens, cost)) if subscription.plan.is_quota_exhausted(tokens, cost) => {
+                return (user.get_token_balance() > 0).then_some(true);
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!(error = %e, "Failed load monthly usage for user {}", user.get_id()),
+        }
+    }
+    Some(false)
+}
+
+/// Counts tokens of a model call against the owner's limits and plan.
+async fn record_usage(pool: &Pool<Postgres>, user_id: i64, response: &ChatResponse, from_balance: bool) {
+    if let Err(e) = db::add_spends(pool, user_id, response.tokens_spent as i32).await {
+        tracing::error!(error = %e, "Failed update tokens spent for user {}", user_id);
+    }
+    if let Err(e) = db::insert_llm_usage(pool, user_id, response).await {
+        tracing::error!(error = %e, "Failed record usage for user {}", user_id);
+    }
+    if from_balance {
+        if let Err(e) = db::spend_balance(pool, user_id, response.tokens_spent as i32).await {
+            tracing::error!(error = %e, "Failed charge token balance of user {}", user_id);
+        }
+    }
+}
+
+/// Runs after the answer is generated, so extraction doesn't delay the reply.
+async fn capture_lead(pool: Pool<Postgres>, user_id: i64, sender_id: String, messages: Vec<Message>, lang: Lang) {
+    // Reloaded so the spend includes the answer that was just generated
+    let user = match db::load_user_from_chat_id(&pool, user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed load user {} for lead capture", user_id);
+            return;
+        }
+    };
+    let Some(from_balance) = check_budget(&pool, &user).await else {
+        return;
+    };
+    match lead::capture(&pool, &get_plan_config(&user), user_id, &sender_id, messages, lang).await {
+        Ok(Some(response)) => record_usage(&pool, user_id, &response, from_balance).await,
+        Ok(None) => {}
+        Err(e) => tracing::error!(error = %e, "Failed capture lead for user {}", user_id),
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
+    logging::init();
+    tracing::debug!(config = ?config::get(), "Loaded config");
 
-    let pool = db::create_pool().await;
-    db::migrate(&pool).await.expect("failed migrations");
+    if env::args().nth(1).as_deref() == Some("healthcheck") {
+        std::process::exit(health::probe().await);
+    }
 
+    secrets::init();
+    let pool = db::create_pool().expect("Invalid DATABASE_URL");
     let token = env::var("TG_TOKEN").expect("TG_TOKEN is not set");
     let client = Client::new(token).expect("Failed to create API");
 
-    log::info!("Bot starting...");
-    LongPoll::new(client.clone(), Handler { client, pool }).run().await;
-}
\ No newline at end of file
+    // Up before the dependency waits, so /readyz shows which one is down
+    let started = Arc::new(AtomicBool::new(false));
+    let (stop_workers, workers_stopped) = watch::channel(false);
+    let status = tokio::spawn(status::serve(
+        metrics::router(pool.clone()).merge(health::router(pool.clone(), client.clone(), started.clone())),
+        workers_stopped.clone(),
+    ));
+
+    health::wait_for("Postgres", || health::check_postgres(&pool)).await;
+    db::migrate(&pool).await.expect("failed migrations");
+
+    if env::args().nth(1).as_deref() == Some("rotate-secrets") {
+        let updated = secrets::seal_stored_keys(&pool, true).await.expect("Failed rotate secrets");
+        tracing::info!("Re-sealed {updated} API keys with the current master key");
+        return;
+    }
+    match secrets::seal_stored_keys(&pool, false).await.expect("Failed seal stored API keys") {
+        0 => {}
+        updated => tracing::info!("Sealed {updated} stored API keys"),
+    }
+
+    health::wait_for("Redis", health::check_redis).await;
+    health::wait_for("Telegram", || health::check_telegram(&client)).await;
+    started.store(true, Ordering::Relaxed);
+    let tracker = Tracker::default();
+
+    let queue = tokio::spawn(queue::run(client.clone(), pool.clone(), workers_stopped.clone()));
+    let retention = tokio::spawn(retention::run(pool.clone(), workers_stopped));
+
+    let llm_limiter = Arc::new(LlmLimiter::default());
+    let new_handler = || Handler {
+        client: client.clone(),
+        pool: pool.clone(),
+        tracker: tracker.clone(),
+        llm_limiter: llm_limiter.clone(),
+    };
+
+    let resumer = Arc::new(new_handler());
+    for reply in db::take_pending_replies(&pool).await.expect("Failed load pending replies") {
+        let resumer = resumer.clone();
+        tokio::spawn(async move { resumer.resume(reply).await });
+    }
+
+    let dispatcher = Arc::new(Dispatcher::new(new_handler()));
+    tracing::info!("Bot starting...");
+    match webhook::WebhookConfig::from_env() {
+        Some(config) => webhook::run(&client, dispatcher.clone(), config, shutdown::signal()).await,
+        None => {
+            health::wait_for("Telegram", || async { Ok(client.execute(DeleteWebhook::default()).await?) }).await;
+            dispatcher.poll(&client, shutdown::signal()).await;
+        }
+    }
+
+    tracing::info!("Waiting for in-flight replies...");
+    dispatcher.drain(config::get().shutdown_timeout).await;
+    for reply in tracker.drain(config::get().shutdown_timeout).await {
+        if let Err(e) = db::insert_pending_reply(&pool, &reply).await {
+            tracing::error!(error = %e, "Failed persist pending reply");
+        }
+    }
+    let _ = stop_workers.send(true);
+    let _ = queue.await;
+    let _ = retention.await;
+    let _ = status.await;
+    tracing::info!("Bot stopped");
+}
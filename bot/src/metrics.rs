@@ -0,0 +1,105 @@
+use std::sync::OnceLock;
+use axum::{Extension, Router};
+use axum::http::StatusCode;
+use axum::routing;
+use prometheus::{
+    exponential_buckets, CounterVec, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use sqlx::{Pool, Postgres};
+use tgbot::types::{Update, UpdateType};
+use crate::db;
+
+pub struct Metrics {
+    registry: Registry,
+    pub updates: IntCounterVec,
+    pub answers: IntCounterVec,
+    pub llm_latency: HistogramVec,
+    pub tokens: IntCounterVec,
+    pub cost: CounterVec,
+    pub errors: IntCounterVec,
+    scheduled_replies: IntGauge,
+    connections: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("bot".to_string()), None).expect("Valid metrics prefix");
+        let metrics = Self {
+            updates: IntCounterVec::new(
+                Opts::new("updates_received_total", "Telegram updates received by type"), &["type"],
+            ).unwrap(),
+            answers: IntCounterVec::new(
+                Opts::new("answers_sent_total", "Answers delivered to customers by result"), &["result"],
+            ).unwrap(),
+            llm_latency: HistogramVec::new(
+                HistogramOpts::new("llm_request_duration_seconds", "LLM request duration including retries")
+                    .buckets(exponential_buckets(0.25, 2.0, 10).unwrap()),
+                &["model", "result"],
+            ).unwrap(),
+            tokens: IntCounterVec::new(
+                Opts::new("llm_tokens_total", "Tokens spent by model"), &["model"],
+            ).unwrap(),
+            cost: CounterVec::new(
+                Opts::new("llm_cost_usd_total", "Estimated LLM cost in USD by model"), &["model"],
+            ).unwrap(),
+            errors: IntCounterVec::new(
+                Opts::new("errors_total", "Errors by source"), &["source"],
+            ).unwrap(),
+            scheduled_replies: IntGauge::new("scheduled_replies", "Generated answers waiting to be sent").unwrap(),
+            connections: IntGauge::new("active_business_connections", "Enabled business connections").unwrap(),
+            registry,
+        };
+        metrics.registry.register(Box::new(metrics.updates.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.answers.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.llm_latency.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.tokens.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.cost.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.errors.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.scheduled_replies.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.connections.clone())).unwrap();
+        metrics
+    }
+}
+
+pub fn get() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+pub fn update_type(update: &Update) -> &'static str {
+    match update.update_type {
+        UpdateType::BusinessConnection(_) => "business_connection",
+        UpdateType::BusinessMessage(_) => "business_message",
+        UpdateType::EditedBusinessMessage(_) => "edited_business_message",
+        UpdateType::DeletedBusinessMessages(_) => "deleted_business_messages",
+        UpdateType::Message(_) => "message",
+        UpdateType::PreCheckoutQuery(_) => "pre_checkout_query",
+        _ => "other",
+    }
+}
+
+pub fn router(pool: Pool<Postgres>) -> Router {
+    Router::new()
+        .route("/metrics", routing::get(export))
+        .layer(Extension(pool))
+}
+
+async fn export(Extension(pool): Extension<Pool<Postgres>>) -> Result<String, StatusCode> {
+    let metrics = get();
+    match db::count_scheduled_replies(&pool).await {
+        Ok(count) => metrics.scheduled_replies.set(count),
+        Err(e) => tracing::error!(error = %e, "Failed count scheduled replies"),
+    }
+    match db::count_active_users(&pool).await {
+        Ok(count) => metrics.connections.set(count),
+        Err(e) => tracing::error!(error = %e, "Failed count active users"),
+    }
+
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&metrics.registry.gather(), &mut buffer).map_err(|e| {
+        tracing::error!(error = %e, "Failed encode metrics");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    String::from_utf8(buffer).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
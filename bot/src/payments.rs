@@ -0,0 +1,124 @@
+use sqlx::{Pool, Postgres};
+use tgbot::types::{AnswerPreCheckoutQuery, LabeledPrice, PreCheckoutQuery, SendInvoice, SuccessfulPayment};
+use crate::{config, db};
+use crate::error::Result;
+use crate::i18n::{t, tf, Lang};
+
+/// Telegram Stars, digital goods can only be sold for them.
+const CURRENCY: &str = "XTR";
+
+/// What an invoice sells, kept in its payload.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Product {
+    Plan(String),
+    Tokens(i64),
+}
+
+impl Product {
+    /// Parses `/buy <plan>` and `/buy tokens`.
+    pub fn from_command(text: &str) -> Option<Self> {
+        match text.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["/buy", "tokens"] => Some(Product::Tokens(config::get().topup.map_or(0, |(tokens, _)| tokens))),
+            ["/buy", name] => Some(Product::Plan(name.to_string())),
+            _ => None,
+        }
+    }
+
+    fn from_payload(payload: &str) -> Option<Self> {
+        match payload.split_once(':')? {
+            ("plan", name) => Some(Product::Plan(name.to_string())),
+            ("tokens", tokens) => tokens.parse().ok().map(Product::Tokens),
+            _ => None,
+        }
+    }
+
+    fn to_payload(&self) -> String {
+        match self {
+            Product::Plan(name) => format!("plan:{name}"),
+            Product::Tokens(tokens) => format!("tokens:{tokens}"),
+        }
+    }
+
+    /// Current price in Stars, `None` if the product is not on sale.
+    async fn get_price(&self, pool: &Pool<Postgres>) -> Result<Option<i32>> {
+        Ok(match self {
+            Product::Plan(name) => db::load_plan(pool, name).await?.and_then(|plan| plan.get_price_stars()),
+            Product::Tokens(tokens) => config::get().topup.filter(|(topup, _)| topup == tokens).map(|(_, price)| price),
+        })
+    }
+}
+
+/// Products on sale, one `/buy` line each.
+pub async fn describe_offers(pool: &Pool<Postgres>, lang: Lang) -> Result<Vec<String>> {
+    let mut offers: Vec<String> = db::load_plans(pool).await?.iter()
+        .filter(|plan| plan.get_price_stars().is_some())
+        .map(|plan| tf(lang, "buy-plan", &[
+            ("name", plan.get_name()),
+            ("price", &tf(lang, "plan-price", &[
+                ("price", &plan.get_price_stars().unwrap_or_default().to_string()),
+                ("days", &plan.get_period_days().to_string()),
+            ])),
+            ("description", &plan.describe(lang)),
+        ]))
+        .collect();
+    if let Some((tokens, price)) = config::get().topup {
+        offers.push(tf(lang, "buy-tokens", &[("tokens", &tokens.to_string()), ("price", &price.to_string())]));
+    }
+    Ok(offers)
+}
+
+pub async fn invoice(pool: &Pool<Postgres>, chat_id: i64, product: &Product, lang: Lang) -> Result<SendInvoice> {
+    let price = product.get_price(pool).await?.ok_or("unknown-product")?;
+    let (title, description) = match product {
+        Product::Plan(name) => {
+            let plan = db::load_plan(pool, name).await?.ok_or("unknown-product")?;
+            (
+                tf(lang, "invoice-plan-title", &[("name", name)]),
+                tf(lang, "invoice-plan-description", &[("name", name), ("days", &plan.get_period_days().to_string())]),
+            )
+        }
+        Product::Tokens(tokens) => (
+            tf(lang, "invoice-tokens-title", &[("tokens", &tokens.to_string())]),
+            tf(lang, "invoice-tokens-description", &[("tokens", &tokens.to_string())]),
+        ),
+    };
+    Ok(SendInvoice::new(
+        chat_id,
+        title.clone(),
+        description,
+        product.to_payload(),
+        CURRENCY,
+        [LabeledPrice::new(price.into(), title)],
+    ))
+}
+
+/// Confirms the checkout only if the product is still on sale for the invoiced price.
+pub async fn check(pool: &Pool<Postgres>, query: &PreCheckoutQuery, lang: Lang) -> Result<AnswerPreCheckoutQuery> {
+    let Some(product) = Product::from_payload(&query.invoice_payload) else {
+        return Ok(AnswerPreCheckoutQuery::error(query.id.clone(), t(lang, "payment-unavailable")));
+    };
+    let price = product.get_price(pool).await?;
+    let user = db::load_user_from_chat_id(pool, query.from.id.into()).await?;
+    Ok(match query.currency == CURRENCY
+        && price.is_some_and(|price| i64::from(price) == query.total_amount)
+        && user.is_some_and(|user| !user.is_blocked()) {
+        true => AnswerPreCheckoutQuery::ok(query.id.clone()),
+        false => AnswerPreCheckoutQuery::error(query.id.clone(), t(lang, "payment-unavailable")),
+    })
+}
+
+/// Applies a completed payment, returns the reply for the owner or `None` if it was already applied.
+pub async fn complete(pool: &Pool<Postgres>, user_id: i64, payment: &SuccessfulPayment, lang: Lang) -> Result<Option<String>> {
+    Ok(match Product::from_payload(&payment.invoice_payload).ok_or("payment-failed")? {
+        Product::Plan(name) => {
+            let period_days = db::load_plan(pool, &name).await?.ok_or("payment-failed")?.get_period_days();
+            db::apply_plan_payment(pool, user_id, payment, &name, period_days).await?
+                .map(|expires_at| tf(lang, "plan-purchased", &[
+                    ("name", &name),
+                    ("date", &expires_at.format("%Y-%m-%d").to_string()),
+                ]))
+        }
+        Product::Tokens(tokens) => db::apply_tokens_payment(pool, user_id, payment, tokens).await?
+            .then(|| tf(lang, "tokens-purchased", &[("tokens", &tokens.to_string())])),
+    })
+}
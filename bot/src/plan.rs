@@ -0,0 +1,211 @@
+use std::env;
+use chrono::{DateTime, Utc};
+use derivative::Derivative;
+use crate::db::PlanRow;
+use crate::error::Error;
+use crate::i18n::{tf, Lang};
+use crate::user::ALLOWED_MODELS;
+
+const DEFAULT_TRIAL_PLAN: &str = "trial";
+const FEATURES: [&str; 2] = ["voice", "rag"];
+const DEFAULT_PERIOD_DAYS: i32 = 30;
+
+/// Plan given to new connections, from `TRIAL_PLAN`.
+pub fn get_trial_plan() -> String {
+    env::var("TRIAL_PLAN").unwrap_or(DEFAULT_TRIAL_PLAN.to_string())
+}
+
+/// Operator-defined limits of a business. `None` and empty lists mean no restriction.
+#[derive(Debug, Clone, Derivative)]
+#[derivative(Default)]
+pub struct Plan {
+    name: String,
+    allowed_models: Vec<String>,
+    monthly_tokens: Option<i64>,
+    /// USD, counted for OpenAI models only.
+    monthly_cost: Option<f64>,
+    max_history_length: Option<usize>,
+    features: Vec<String>,
+    /// How long the plan lasts when given to a new connection.
+    trial_days: Option<i32>,
+    /// Telegram Stars per period, plans without a price can't be bought.
+    price_stars: Option<i32>,
+    #[derivative(Default(value = "DEFAULT_PERIOD_DAYS"))]
+    period_days: i32,
+}
+
+/// Plan assigned to a business owner.
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    pub plan: Plan,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Subscription {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+}
+
+impl Plan {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string(), ..Default::default() }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn allows_model(&self, model: &str) -> bool {
+        self.allowed_models.is_empty() || self.allowed_models.iter().any(|allowed| allowed == model)
+    }
+
+    /// The model used instead of a model the plan does not allow.
+    pub fn get_default_model(&self) -> Option<&str> {
+        self.allowed_models.first().map(|model| model.as_str())
+    }
+
+    pub fn get_price_stars(&self) -> Option<i32> {
+        self.price_stars
+    }
+
+    pub fn get_period_days(&self) -> i32 {
+        self.period_days
+    }
+
+    pub fn get_max_history_length(&self) -> Option<usize> {
+        self.max_history_length
+    }
+
+    pub fn check_model(&self, model: &str) -> Result<(), &'static str> {
+        match self.allows_model(model) {
+            true => Ok(()),
+            false => Err("model-not-in-plan"),
+        }
+    }
+
+    pub fn check_history_length(&self, length: usize) -> Result<(), &'static str> {
+        match self.max_history_length.is_some_and(|max| length > max) {
+            true => Err("history-length-over-plan"),
+            false => Ok(()),
+        }
+    }
+
+    pub fn has_quota(&self) -> bool {
+        self.monthly_tokens.is_some() || self.monthly_cost.is_some()
+    }
+
+    pub fn is_quota_exhausted(&self, tokens: i64, cost: f64) -> bool {
+        self.monthly_tokens.is_some_and(|quota| tokens >= quota)
+            || self.monthly_cost.is_some_and(|quota| cost >= quota)
+    }
+
+    /// Changes one field by name, as in `/edit_plan <name> <field> <value>`; `[none]` removes the restriction.
+    pub fn set(&mut self, field: &str, value: &str) -> Result<(), &'static str> {
+        let none = value.trim().to_lowercase() == "[none]";
+        match field {
+            "models" => {
+                let models = parse_list(value, none);
+                if models.iter().any(|model| !ALLOWED_MODELS.contains(&model.as_str())) {
+                    return Err("invalid-model");
+                }
+                self.allowed_models = models;
+            }
+            "tokens" => self.monthly_tokens = parse_limit(value, none)?,
+            "cost" => self.monthly_cost = parse_limit(value, none)?,
+            "history" => self.max_history_length = parse_limit(value, none)?,
+            "trial_days" => self.trial_days = parse_limit(value, none)?,
+            "price" => self.price_stars = parse_limit(value, none)?.filter(|price| *price > 0),
+            "period_days" => {
+                self.period_days = parse_limit(value, false)?.filter(|days| *days > 0).ok_or("invalid-plan-value")?;
+            }
+            "features" => {
+                let features = parse_list(value, none);
+                if features.iter().any(|feature| !FEATURES.contains(&feature.as_str())) {
+                    return Err("invalid-feature");
+                }
+                self.features = features;
+            }
+            _ => return Err("invalid-plan-field"),
+        }
+        Ok(())
+    }
+
+    pub fn describe(&self, lang: Lang) -> String {
+        let or_any = |value: Option<String>| value.unwrap_or("∞".to_string());
+        tf(lang, "plan-description", &[
+            ("name", &self.name),
+            ("models", &match self.allowed_models.is_empty() {
+                true => "∞".to_string(),
+                false => self.allowed_models.join(", "),
+            }),
+            ("tokens", &or_any(self.monthly_tokens.map(|value| value.to_string()))),
+            ("cost", &or_any(self.monthly_cost.map(|value| format!("${value:.2}")))),
+            ("history", &or_any(self.max_history_length.map(|value| value.to_string()))),
+            ("features", &match self.features.is_empty() {
+                true => "---".to_string(),
+                false => self.features.join(", "),
+            }),
+            ("price", &match self.price_stars {
+                Some(price) => tf(lang, "plan-price", &[("price", &price.to_string()), ("days", &self.period_days.to_string())]),
+                None => "---".to_string(),
+            }),
+        ])
+    }
+}
+
+fn parse_list(value: &str, none: bool) -> Vec<String> {
+    match none {
+        true => vec![],
+        false => value.split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect(),
+    }
+}
+
+fn parse_limit<T: std::str::FromStr + PartialOrd + Default>(value: &str, none: bool) -> Result<Option<T>, &'static str> {
+    match none {
+        true => Ok(None),
+        false => value.parse().ok()
+            .filter(|value| *value >= T::default())
+            .map(Some)
+            .ok_or("invalid-plan-value"),
+    }
+}
+
+impl TryFrom<PlanRow> for Plan {
+    type Error = Error;
+
+    fn try_from(row: PlanRow) -> Result<Self, Self::Error> {
+        Ok(Self {
+            name: row.id,
+            allowed_models: serde_json::from_value(row.allowed_models)?,
+            monthly_tokens: row.monthly_tokens,
+            monthly_cost: row.monthly_cost,
+            max_history_length: row.max_history_length.map(|value| value as usize),
+            features: serde_json::from_value(row.features)?,
+            trial_days: row.trial_days,
+            price_stars: row.price_stars,
+            period_days: row.period_days,
+        })
+    }
+}
+
+impl TryFrom<&Plan> for PlanRow {
+    type Error = Error;
+
+    fn try_from(plan: &Plan) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: plan.name.clone(),
+            allowed_models: serde_json::to_value(&plan.allowed_models)?,
+            monthly_tokens: plan.monthly_tokens,
+            monthly_cost: plan.monthly_cost,
+            max_history_length: plan.max_history_length.map(|value| value as i32),
+            features: serde_json::to_value(&plan.features)?,
+            trial_days: plan.trial_days,
+            price_stars: plan.price_stars,
+            period_days: plan.period_days,
+        })
+    }
+}
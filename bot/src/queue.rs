@@ -0,0 +1,172 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres};
+use tgbot::api::{Client, ExecuteError};
+use tgbot::types::{ChatAction, ParseMode, ReplyParameters, SendChatAction, SendMessage};
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration};
+use crate::{db, format, metrics};
+use crate::error::Result;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Telegram shows the typing status for about five seconds.
+pub const TYPING_LEAD_SECONDS: i32 = 5;
+/// How long a claimed reply is hidden from other workers, after that it is sent again.
+pub const CLAIM_SECONDS: i32 = 300;
+const MAX_SEND_ATTEMPTS: i32 = 5;
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// A generated answer waiting in the queue for its send time.
+#[derive(Debug, Clone)]
+pub struct ScheduledReply {
+    pub chat_id: i64,
+    pub business_id: Option<String>,
+    /// Customer message the reply answers.
+    pub message_id: Option<i64>,
+    /// Set when the answer is sent as a reply to the customer message.
+    pub reply_to_message_id: Option<i64>,
+    /// Model Markdown, rendered and split into messages on sending.
+    pub text: String,
+    pub footer: Option<String>,
+    pub send_at: DateTime<Utc>,
+}
+
+/// A due reply claimed from the queue, removed only once it is sent or given up.
+#[derive(Debug)]
+pub struct ClaimedReply {
+    pub id: i64,
+    /// Failed attempts so far.
+    pub attempts: i32,
+    /// Parts of the split reply sent before an attempt failed.
+    pub sent_parts: usize,
+    pub reply: ScheduledReply,
+}
+
+/// Dispatches due replies from the queue until `stop` turns true.
+pub async fn run(client: Client, pool: Pool<Postgres>, mut stop: watch::Receiver<bool>) {
+    loop {
+        if let Err(e) = dispatch(&client, &pool).await {
+            tracing::error!(error = %e, "Failed dispatch scheduled replies");
+        }
+        tokio::select! {
+            _ = sleep(POLL_INTERVAL) => {}
+            _ = stop.changed() => {}
+        }
+        if *stop.borrow() {
+            break;
+        }
+    }
+}
+
+async fn dispatch(client: &Client, pool: &Pool<Postgres>) -> Result<()> {
+    for (chat_id, business_id) in db::take_typing_due(pool).await? {
+        let mut method = SendChatAction::new(chat_id, ChatAction::Typing);
+        if let Some(business_id) = &business_id {
+            method = method.with_business_connection_id(business_id);
+        }
+        let _ = client.execute(method).await;
+    }
+
+    for claimed in db::claim_due_replies(pool).await? {
+        if let Err(e) = deliver(client, pool, &claimed).await {
+            tracing::error!(error = %e, "Failed update scheduled reply {}", claimed.id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends a claimed reply, retrying transient failures with backoff until the attempts run out.
+#[tracing::instrument(name = "send", skip_all, fields(chat_id = claimed.reply.chat_id, message_id = claimed.reply.message_id))]
+async fn deliver(client: &Client, pool: &Pool<Postgres>, claimed: &ClaimedReply) -> Result<()> {
+    let reply = &claimed.reply;
+    let parts = format::render(&reply.text, reply.footer.as_deref());
+    let count = parts.len();
+    for (index, part) in parts.into_iter().enumerate().skip(claimed.sent_parts) {
+        if let Err(e) = send_rendered(client, reply, index, part).await {
+            if is_transient(&e) && claimed.attempts + 1 < MAX_SEND_ATTEMPTS {
+                let delay = retry_delay(&e, claimed.attempts);
+                tracing::warn!(error = %e, "Failed send scheduled reply, retrying in {}s", delay.as_secs());
+                return db::retry_scheduled_reply(pool, claimed.id, delay).await;
+            }
+            tracing::error!(error = %e, "Failed send scheduled reply");
+            metrics::get().answers.with_label_values(&["failed"]).inc();
+            return db::delete_scheduled_reply(pool, claimed.id).await;
+        }
+        if index + 1 < count {
+            db::set_sent_parts(pool, claimed.id, index + 1).await?;
+        }
+    }
+    metrics::get().answers.with_label_values(&["sent"]).inc();
+    db::delete_scheduled_reply(pool, claimed.id).await
+}
+
+/// Sends a reply right away, bypassing the queue, when it can't be stored.
+#[tracing::instrument(name = "send", skip_all, fields(chat_id = reply.chat_id, message_id = reply.message_id))]
+pub async fn send(client: &Client, reply: ScheduledReply) {
+    for (index, part) in format::render(&reply.text, reply.footer.as_deref()).into_iter().enumerate() {
+        if let Err(e) = send_rendered(client, &reply, index, part).await {
+            tracing::error!(error = %e, "Failed send reply");
+            metrics::get().answers.with_label_values(&["failed"]).inc();
+            return;
+        }
+    }
+    metrics::get().answers.with_label_values(&["sent"]).inc();
+}
+
+/// Sends one rendered part, as plain text if Telegram rejects the HTML.
+async fn send_rendered(
+    client: &Client,
+    reply: &ScheduledReply,
+    index: usize,
+    part: String,
+) -> std::result::Result<(), ExecuteError> {
+    // Only the first part quotes the customer message
+    let reply_to = reply.reply_to_message_id.filter(|_| index == 0);
+    if let Err(e) = send_part(client, reply, part.clone(), Some(ParseMode::Html), reply_to).await {
+        tracing::warn!(error = %e, "Failed send formatted reply, sending plain text");
+        return send_part(client, reply, format::strip_tags(&part), None, reply_to).await;
+    }
+    Ok(())
+}
+
+/// Network failures, rate limits and server errors may pass on retry, other rejections won't.
+fn is_transient(error: &ExecuteError) -> bool {
+    match error {
+        ExecuteError::Http(_) | ExecuteError::TooManyRequests => true,
+        ExecuteError::Response(error) => error.can_retry() || error.error_code().is_some_and(|code| code >= 500),
+        ExecuteError::Payload(_) => false,
+    }
+}
+
+/// The wait Telegram asks for, otherwise exponential backoff.
+fn retry_delay(error: &ExecuteError, attempts: i32) -> Duration {
+    let retry_after = match error {
+        ExecuteError::Response(error) => error.retry_after(),
+        _ => None,
+    };
+    retry_after.map(Duration::from_secs).unwrap_or_else(|| {
+        BASE_RETRY_DELAY.saturating_mul(2u32.saturating_pow(attempts as u32)).min(MAX_RETRY_DELAY)
+    })
+}
+
+async fn send_part(
+    client: &Client,
+    reply: &ScheduledReply,
+    text: String,
+    parse_mode: Option<ParseMode>,
+    reply_to: Option<i64>,
+) -> std::result::Result<(), ExecuteError> {
+    let mut method = SendMessage::new(reply.chat_id, text);
+    if let Some(parse_mode) = parse_mode {
+        method = method.with_parse_mode(parse_mode);
+    }
+    if let Some(business_id) = &reply.business_id {
+        method = method.with_business_connection_id(business_id);
+    }
+    if let Some(message_id) = reply_to {
+        // The customer may have deleted the message meanwhile
+        method = method.with_reply_parameters(ReplyParameters::new(message_id).with_allow_sending_without_reply(true));
+    }
+    client.execute(method).await.map(|_| ())
+}
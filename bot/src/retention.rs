@@ -0,0 +1,38 @@
+use sqlx::{Pool, Postgres};
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration};
+use crate::{config, db};
+use crate::conversation::ConversationManager;
+use crate::error::Result;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(3_600);
+
+/// Purges owners whose business connection stayed disabled longer than the retention period.
+pub async fn run(pool: Pool<Postgres>, mut stop: watch::Receiver<bool>) {
+    let days = config::get().retention_days;
+    loop {
+        match db::purge_disabled_users(&pool, days).await {
+            Ok(ids) if !ids.is_empty() => {
+                for id in &ids {
+                    if let Err(e) = clear_history(*id).await {
+                        tracing::error!(error = %e, "Failed clear history of purged user {id}");
+                    }
+                }
+                tracing::info!("Purged {} users disabled for over {days} days", ids.len());
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!(error = %e, "Failed purge disabled users"),
+        }
+        tokio::select! {
+            _ = sleep(CHECK_INTERVAL) => {}
+            _ = stop.changed() => {}
+        }
+        if *stop.borrow() {
+            break;
+        }
+    }
+}
+
+async fn clear_history(user_id: i64) -> Result<()> {
+    ConversationManager::for_owner(user_id)?.clear().await
+}
@@ -0,0 +1,241 @@
+use std::env;
+use std::fmt;
+use std::fs::read_to_string;
+use std::sync::OnceLock;
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use aes_gcm::aead::{Aead, AeadCore, OsRng, Payload};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use crate::db;
+use crate::error::{Error, Result};
+
+const NONCE_SIZE: usize = 12;
+/// Sealed secrets of this version bind the data key and the value to the owner id,
+/// version 0 was sealed without associated data.
+const BOUND_VERSION: u8 = 1;
+
+/// Master keys used to wrap per-secret data keys. The current one seals new secrets,
+/// the previous ones are only used to open secrets sealed before a rotation.
+struct MasterKeys {
+    current: Key<Aes256Gcm>,
+    previous: Vec<Key<Aes256Gcm>>,
+}
+
+fn parse_key(value: &str) -> Key<Aes256Gcm> {
+    let bytes = BASE64.decode(value.trim()).expect("Master key must be base64");
+    assert_eq!(bytes.len(), 32, "Master key must be 32 bytes");
+    *Key::<Aes256Gcm>::from_slice(&bytes)
+}
+
+fn master_keys() -> &'static MasterKeys {
+    static KEYS: OnceLock<MasterKeys> = OnceLock::new();
+    KEYS.get_or_init(|| {
+        let current = env::var("SECRETS_MASTER_KEY").ok()
+            .or_else(|| {
+                let path = env::var("SECRETS_MASTER_KEY_FILE").ok()?;
+                Some(read_to_string(path).expect("Failed read SECRETS_MASTER_KEY_FILE"))
+            })
+            .expect("SECRETS_MASTER_KEY or SECRETS_MASTER_KEY_FILE must be set");
+        let previous = env::var("SECRETS_PREVIOUS_MASTER_KEYS").unwrap_or_default()
+            .split(',')
+            .filter(|value| !value.trim().is_empty())
+            .map(parse_key)
+            .collect();
+        MasterKeys { current: parse_key(&current), previous }
+    })
+}
+
+/// Loads the master keys, panics without the current one so API keys are never stored unencrypted.
+pub fn init() {
+    master_keys();
+}
+
+/// Operator-wide API key used by owners who have not set their own.
+pub fn default_api_key() -> Option<String> {
+    env::var("DEFAULT_API_KEY").ok().filter(|value| !value.is_empty())
+}
+
+/// A provider secret as stored in the database: sealed with envelope encryption,
+/// or plain text when written before encryption was enabled.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Secret {
+    Sealed(SealedSecret),
+    Plain(String),
+}
+
+/// Both `dek` and `data` are base64 of nonce followed by ciphertext.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SealedSecret {
+    #[serde(default)]
+    version: u8,
+    dek: String,
+    data: String,
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Secret::Sealed(_) => write!(f, "Secret::Sealed(..)"),
+            Secret::Plain(_) => write!(f, "Secret::Plain(..)"),
+        }
+    }
+}
+
+impl Secret {
+    /// Seals the value of the owner with a fresh data key.
+    pub fn seal(value: &str, owner_id: i64) -> Result<Self> {
+        master_keys().seal(value, owner_id)
+    }
+
+    pub fn reveal(&self, owner_id: i64) -> Result<String> {
+        master_keys().reveal(self, owner_id)
+    }
+
+    /// Plain and unbound secrets are sealed again at startup.
+    fn needs_reseal(&self) -> bool {
+        !matches!(self, Secret::Sealed(sealed) if sealed.version == BOUND_VERSION)
+    }
+}
+
+impl MasterKeys {
+    fn seal(&self, value: &str, owner_id: i64) -> Result<Secret> {
+        let aad = owner_id.to_be_bytes();
+        let dek = Aes256Gcm::generate_key(OsRng);
+        Ok(Secret::Sealed(SealedSecret {
+            version: BOUND_VERSION,
+            dek: encrypt(&self.current, &dek, &aad)?,
+            data: encrypt(&dek, value.as_bytes(), &aad)?,
+        }))
+    }
+
+    fn reveal(&self, secret: &Secret, owner_id: i64) -> Result<String> {
+        let sealed = match secret {
+            Secret::Plain(value) => return Ok(value.clone()),
+            Secret::Sealed(sealed) => sealed,
+        };
+        let owner = owner_id.to_be_bytes();
+        let aad: &[u8] = match sealed.version {
+            0 => &[],
+            BOUND_VERSION => &owner,
+            _ => return Err(Error::internal("Unknown secret version")),
+        };
+        let dek = std::iter::once(&self.current).chain(&self.previous)
+            .find_map(|master_key| decrypt(master_key, &sealed.dek, aad).ok())
+            .ok_or_else(|| Error::internal("No master key opens the secret"))?;
+        let data = decrypt(Key::<Aes256Gcm>::from_slice(&dek), &sealed.data, aad)?;
+        String::from_utf8(data).map_err(|_| Error::internal("Secret is not UTF-8"))
+    }
+
+    /// Re-seals with the current master key, used for migration and key rotation.
+    fn reseal(&self, secret: &Secret, owner_id: i64) -> Result<Secret> {
+        self.seal(&self.reveal(secret, owner_id)?, owner_id)
+    }
+}
+
+fn encrypt(key: &Key<Aes256Gcm>, plaintext: &[u8], aad: &[u8]) -> Result<String> {
+    let nonce = Aes256Gcm::generate_nonce(OsRng);
+    let ciphertext = Aes256Gcm::new(key).encrypt(&nonce, Payload { msg: plaintext, aad })
+        .map_err(|_| Error::internal("Failed encrypt secret"))?;
+    Ok(BASE64.encode([nonce.as_slice(), &ciphertext].concat()))
+}
+
+fn decrypt(key: &Key<Aes256Gcm>, value: &str, aad: &[u8]) -> Result<Vec<u8>> {
+    let bytes = BASE64.decode(value).map_err(|_| Error::internal("Secret is not base64"))?;
+    if bytes.len() < NONCE_SIZE {
+        return Err(Error::internal("Secret is too short"));
+    }
+    let (nonce, ciphertext) = bytes.split_at(NONCE_SIZE);
+    Aes256Gcm::new(key).decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| Error::internal("Failed decrypt secret"))
+}
+
+/// Seals stored plain and unbound API keys with the current master key, and with `rotate`
+/// re-seals the rest too. Runs at startup before any update is handled, as SQL migrations
+/// can't reach the master key. Returns the number of updated keys.
+pub async fn seal_stored_keys(pool: &Pool<Postgres>, rotate: bool) -> Result<usize> {
+    let master_keys = master_keys();
+    let mut updated = 0;
+    for (id, old) in db::load_api_keys(pool).await? {
+        let secret: Secret = serde_json::from_value(old.clone())?;
+        if !secret.needs_reseal() && !rotate {
+            continue;
+        }
+        let new = match master_keys.reseal(&secret, id) {
+            Ok(new) => serde_json::to_value(new)?,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed re-seal API key of user {id}");
+                continue;
+            }
+        };
+        if db::replace_api_key(pool, id, old, new).await? {
+            updated += 1;
+        }
+    }
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> Key<Aes256Gcm> {
+        *Key::<Aes256Gcm>::from_slice(&[byte; 32])
+    }
+
+    fn master_keys(current: u8, previous: &[u8]) -> MasterKeys {
+        MasterKeys { current: key(current), previous: previous.iter().copied().map(key).collect() }
+    }
+
+    #[test]
+    fn seal_and_reveal() {
+        let keys = master_keys(1, &[]);
+        let secret = keys.seal("sk-test", 42).unwrap();
+        assert!(!secret.needs_reseal());
+        assert!(!serde_json::to_string(&secret).unwrap().contains("sk-test"));
+        assert_eq!(keys.reveal(&secret, 42).unwrap(), "sk-test");
+    }
+
+    #[test]
+    fn rotation_opens_with_previous_key() {
+        let secret = master_keys(1, &[]).seal("sk-test", 42).unwrap();
+        let rotated = master_keys(2, &[1]);
+        assert_eq!(rotated.reveal(&secret, 42).unwrap(), "sk-test");
+
+        let resealed = rotated.reseal(&secret, 42).unwrap();
+        let current_only = master_keys(2, &[]);
+        assert_eq!(current_only.reveal(&resealed, 42).unwrap(), "sk-test");
+        assert!(current_only.reveal(&secret, 42).is_err());
+    }
+
+    #[test]
+    fn tampered_secret_is_rejected() {
+        let keys = master_keys(1, &[]);
+        let secret = keys.seal("sk-test", 42).unwrap();
+        assert!(keys.reveal(&secret, 43).is_err());
+
+        let Secret::Sealed(mut sealed) = secret else { unreachable!() };
+        let mut data = BASE64.decode(&sealed.data).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        sealed.data = BASE64.encode(data);
+        assert!(keys.reveal(&Secret::Sealed(sealed), 42).is_err());
+    }
+
+    #[test]
+    fn unbound_secret_is_resealed() {
+        let keys = master_keys(1, &[]);
+        let dek = key(9);
+        let secret: Secret = serde_json::from_value(serde_json::json!({
+            "dek": encrypt(&keys.current, &dek, &[]).unwrap(),
+            "data": encrypt(&dek, b"sk-test", &[]).unwrap(),
+        })).unwrap();
+        assert!(secret.needs_reseal());
+
+        let resealed = keys.reseal(&secret, 42).unwrap();
+        assert!(!resealed.needs_reseal());
+        assert_eq!(keys.reveal(&resealed, 42).unwrap(), "sk-test");
+        assert!(keys.reveal(&resealed, 43).is_err());
+    }
+}
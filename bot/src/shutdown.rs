@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::{DateTime, Utc};
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
+use tokio::sync::Notify;
+use tokio::time::{timeout, Duration};
+use crate::i18n::Lang;
+
+/// A message that has been received but not answered yet.
+#[derive(Debug, Clone)]
+pub struct PendingReply {
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub business_id: Option<String>,
+    pub sender_id: String,
+    /// Telegram id of the customer message, used to match later edits and deletions.
+    pub message_id: Option<i64>,
+    pub message: String,
+    pub lang: Lang,
+    pub send_at: DateTime<Utc>,
+}
+
+/// Keeps track of replies in flight, so they can be drained or persisted on shutdown.
+#[derive(Clone, Default)]
+pub struct Tracker {
+    inner: Arc<TrackerInner>,
+}
+
+#[derive(Default)]
+struct TrackerInner {
+    pending: Mutex<HashMap<u64, PendingReply>>,
+    next_id: AtomicU64,
+    idle: Notify,
+}
+
+pub struct PendingGuard {
+    tracker: Tracker,
+    id: u64,
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        let mut pending = self.tracker.inner.pending.lock().unwrap();
+        pending.remove(&self.id);
+        if pending.is_empty() {
+            self.tracker.inner.idle.notify_waiters();
+        }
+    }
+}
+
+impl Tracker {
+    pub fn track(&self, reply: PendingReply) -> PendingGuard {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        self.inner.pending.lock().unwrap().insert(id, reply);
+        PendingGuard { tracker: self.clone(), id }
+    }
+
+    /// Waits for in-flight replies up to `deadline` and returns the ones still pending.
+    pub async fn drain(&self, deadline: Duration) -> Vec<PendingReply> {
+        let wait = async {
+            loop {
+                let notified = self.inner.idle.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                if self.inner.pending.lock().unwrap().is_empty() {
+                    break;
+                }
+                notified.await;
+            }
+        };
+        if timeout(deadline, wait).await.is_err() {
+            tracing::warn!("Shutdown deadline reached with replies still pending");
+        }
+        self.inner.pending.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// Resolves on SIGTERM or Ctrl+C.
+pub async fn signal() {
+    let mut terminate = unix_signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    tracing::info!("Shutdown signal received");
+}
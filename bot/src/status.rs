@@ -0,0 +1,37 @@
+use std::env;
+use std::net::SocketAddr;
+use axum::Router;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+const DEFAULT_ADDRESS: &str = "0.0.0.0:9090";
+
+pub fn get_address() -> SocketAddr {
+    env::var("STATUS_ADDRESS")
+        .unwrap_or(DEFAULT_ADDRESS.to_string())
+        .parse()
+        .expect("STATUS_ADDRESS must be a socket address")
+}
+
+/// Serves metrics and health endpoints on `STATUS_ADDRESS` until `stop` turns true.
+pub async fn serve(router: Router, mut stop: watch::Receiver<bool>) {
+    let address = get_address();
+    let listener = match TcpListener::bind(address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed bind status address {address}");
+            return;
+        }
+    };
+    tracing::info!("Status server listening on {address}");
+    let shutdown = async move {
+        while !*stop.borrow() {
+            if stop.changed().await.is_err() {
+                break;
+            }
+        }
+    };
+    if let Err(e) = axum::serve(listener, router).with_graceful_shutdown(shutdown).await {
+        tracing::error!(error = %e, "Status server failed");
+    }
+}
@@ -1,36 +1,86 @@
 use std::string::ToString;
+use chrono::{DateTime, Utc};
 use derivative::Derivative;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use crate::{dialogue};
+use tgbot::types::BusinessConnection;
+use crate::{config, dialogue};
+use crate::db::SettingsRow;
+use crate::dialogue::Provider;
+use crate::error::Error;
+use crate::i18n::{t, Lang};
+use crate::plan::{Plan, Subscription};
+use crate::secrets::{self, Secret};
 use crate::conversation::{ConversationManager, DEFAULT_CACHE_DURATION, DEFAULT_CHAR_LIMIT};
 
 
 const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
 const DEFAULT_FOOTER: &str = "[ai generated answer]";
+const MAX_FALLBACK_LENGTH: usize = 200;
+const MAX_FALLBACK_MODELS: usize = 3;
+const MAX_LEAD_FIELDS: usize = 8;
+const MAX_LEAD_FIELD_LENGTH: usize = 50;
+pub const ALLOWED_MODELS: [&str; 4] = ["gpt-3.5-turbo", "gpt-4-turbo", "gpt-4o", "gpt-4o-mini"];
 
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default)]
 pub struct User {
     id: i64,
-    business_id: String,
-    openai: Openai,
+    can_reply: bool,
+    blocked: bool,
+    config: OpenaiConfig,
+    spend: Spend,
+    /// Without a plan the owner has no restrictions, as before plans were introduced.
+    subscription: Option<Subscription>,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
-pub struct Openai {
-    config: OpenaiConfig,
-    spent_tokens: i64,
+/// Token accounting of a business owner.
+#[derive(Debug, Default)]
+pub struct Spend {
+    pub spent_tokens: i64,
+    /// Limit set by the operator, applies on top of the owner's own `max_total_tokens_spent`.
+    pub token_limit: Option<i64>,
+    /// Purchased tokens, used once the monthly quota of the plan is exhausted.
+    pub token_balance: i64,
+}
+
+/// Business connection as reported by Telegram, with the connected owner's profile.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub user_id: i64,
+    pub business_id: String,
+    pub can_reply: bool,
+    pub connected_at: DateTime<Utc>,
+    pub first_name: String,
+    pub last_name: Option<String>,
+    pub username: Option<String>,
+    pub language_code: Option<String>,
+}
+
+impl From<&BusinessConnection> for Connection {
+    fn from(value: &BusinessConnection) -> Self {
+        Self {
+            user_id: value.user_chat_id,
+            business_id: value.id.clone(),
+            can_reply: value.can_reply,
+            connected_at: DateTime::from_timestamp(value.date, 0).unwrap_or_else(Utc::now),
+            first_name: value.user.first_name.clone(),
+            last_name: value.user.last_name.clone(),
+            username: value.user.username.as_ref().map(|username| username.to_string()),
+            language_code: value.user.language_code.clone(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Derivative, Serialize, Deserialize)]
+#[derive(Debug, Clone, Derivative)]
 #[derivative(Default)]
 pub struct OpenaiConfig {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    api_key: Option<String>,
+    /// Row version the settings were loaded with, checked when saving them back.
+    version: i32,
+    /// Owner the settings belong to, the sealed API key is bound to it.
+    owner_id: i64,
+    api_key: Option<Secret>,
     #[derivative(Default(value = "DEFAULT_MODEL.to_string()"))]
     model: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
     prompt: Option<String>,
     #[derivative(Default(value = "4_000"))]
     max_message_length: i32,
@@ -38,21 +88,36 @@ pub struct OpenaiConfig {
     max_total_tokens_spent: i64,
     #[derivative(Default(value = "300"))]
     max_tokens: u16,
-    #[serde(skip_serializing_if = "Option::is_none")]
     conversation: Option<Conversation>,
-    #[serde(skip_serializing_if = "Option::is_none")]
     chatting: Option<Chatting>,
+    sandbox: Option<Sandbox>,
+    language: Option<Lang>,
+    fallbacks: Option<Fallbacks>,
+    fallback_models: Vec<FallbackModel>,
+    reply_threading: bool,
+    /// Details the model collects from customers, empty turns lead capture off.
+    lead_fields: Vec<String>,
+}
+
+/// A model tried when the main one fails. Without `base_url` it is an OpenAI model
+/// called with the owner's key, otherwise a self-hosted OpenAI-compatible endpoint from the operator
+/// allowlist, called without a key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FallbackModel {
+    model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base_url: Option<String>,
 }
 
 
-#[derive(Debug, Clone, Derivative, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
 struct Conversation {
     cache_duration: Option<i64>,
     char_limit: Option<usize>,
 }
 
 
-#[derive(Debug, Clone, Derivative, Serialize, Deserialize)]
+#[derive(Debug, Clone, Derivative)]
 #[derivative(Default)]
 struct Chatting {
     answer_pause: (i32, i32),
@@ -60,17 +125,125 @@ struct Chatting {
     footer: Option<String>,
 }
 
+/// Per-business replies sent to customers instead of an answer.
+/// `None` keeps the built-in reply, an empty string means stay silent.
+#[derive(Debug, Clone, Default)]
+struct Fallbacks {
+    error: Option<String>,
+    too_long: Option<String>,
+    unsupported_media: Option<String>,
+    budget_exhausted: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Fallback {
+    Error,
+    TooLong,
+    UnsupportedMedia,
+    BudgetExhausted,
+}
+
+impl Fallback {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(Fallback::Error),
+            "too_long" => Some(Fallback::TooLong),
+            "unsupported_media" => Some(Fallback::UnsupportedMedia),
+            "budget_exhausted" => Some(Fallback::BudgetExhausted),
+            _ => None,
+        }
+    }
+
+    /// Catalog key of the built-in reply, `None` if the default is to stay silent.
+    fn default_key(&self) -> Option<&'static str> {
+        match self {
+            Fallback::Error => Some("no-answer"),
+            Fallback::TooLong => Some("too-long-message"),
+            Fallback::UnsupportedMedia => Some("only-text"),
+            Fallback::BudgetExhausted => None,
+        }
+    }
+}
+
+impl Fallbacks {
+    fn get_mut(&mut self, kind: Fallback) -> &mut Option<String> {
+        match kind {
+            Fallback::Error => &mut self.error,
+            Fallback::TooLong => &mut self.too_long,
+            Fallback::UnsupportedMedia => &mut self.unsupported_media,
+            Fallback::BudgetExhausted => &mut self.budget_exhausted,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    pause: bool,
+}
+
+impl Sandbox {
+    pub fn new(pause: bool) -> Self {
+        Self { pause }
+    }
+
+    pub fn get_pause(&self) -> bool {
+        self.pause
+    }
+}
+
 impl User {
-    pub fn new(id: i64, business_id: String, openai: Openai) -> Self {
-        Self { id, business_id, openai }
+    pub fn new(
+        id: i64,
+        can_reply: bool,
+        blocked: bool,
+        config: OpenaiConfig,
+        spend: Spend,
+        subscription: Option<Subscription>,
+    ) -> Self {
+        let config = OpenaiConfig { owner_id: id, ..config };
+        Self { id, can_reply, blocked, config, spend, subscription }
+    }
+
+    /// Whether the owner allowed the bot to reply on their behalf.
+    pub fn can_reply(&self) -> bool {
+        self.can_reply
+    }
+
+    /// Blocked by the operator: customers get no answers and settings can't be changed.
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+
+    /// The lower of the owner's and the operator's limits.
+    pub fn get_token_limit(&self) -> i64 {
+        let max_total_tokens_spent = self.config.get_max_total_tokens_spent();
+        self.spend.token_limit.map_or(max_total_tokens_spent, |limit| limit.min(max_total_tokens_spent))
+    }
+
+    pub fn get_token_balance(&self) -> i64 {
+        self.spend.token_balance
     }
 
     pub fn get_config(&self) -> OpenaiConfig {
-        self.openai.config.clone()
+        self.config.clone()
+    }
+
+    pub fn get_subscription(&self) -> Option<&Subscription> {
+        self.subscription.as_ref()
+    }
+
+    pub fn get_plan(&self) -> Option<&Plan> {
+        self.subscription.as_ref().map(|subscription| &subscription.plan)
+    }
+
+    /// The operator key is metered by the plan, so owners without an active one must use their own.
+    pub fn can_use_shared_key(&self) -> bool {
+        secrets::default_api_key().is_some()
+            && self.subscription.as_ref().is_some_and(|subscription| !subscription.is_expired())
     }
 
     pub fn get_openai_spent_tokens(&self) -> i64 {
-        self.openai.spent_tokens
+        self.spend.spent_tokens
     }
 
     pub fn get_id(&self) -> i64 {
@@ -78,28 +251,95 @@ impl User {
     }
 }
 
-impl From<Value> for Openai {
-    fn from(value: Value) -> Self {
-        serde_json::from_value(value).unwrap()
+impl TryFrom<SettingsRow> for OpenaiConfig {
+    type Error = Error;
+
+    fn try_from(row: SettingsRow) -> Result<Self, Self::Error> {
+        let conversation = match (row.cache_duration, row.char_limit) {
+            (None, None) => None,
+            (cache_duration, char_limit) => Some(Conversation {
+                cache_duration,
+                char_limit: char_limit.map(|value| value as usize),
+            }),
+        };
+        let fallbacks = Fallbacks {
+            error: row.fallback_error,
+            too_long: row.fallback_too_long,
+            unsupported_media: row.fallback_unsupported_media,
+            budget_exhausted: row.fallback_budget_exhausted,
+        };
+        Ok(Self {
+            version: row.version,
+            owner_id: 0,
+            api_key: row.api_key.map(serde_json::from_value).transpose()?,
+            model: row.model,
+            prompt: row.prompt,
+            max_message_length: row.max_message_length,
+            max_total_tokens_spent: row.max_total_tokens_spent,
+            max_tokens: row.max_tokens.try_into().map_err(|_| Error::internal("Invalid max_tokens"))?,
+            conversation,
+            chatting: Some(Chatting {
+                answer_pause: (row.answer_pause_min, row.answer_pause_max),
+                footer: row.footer,
+            }),
+            sandbox: row.sandbox_pause.map(Sandbox::new),
+            language: row.language.as_deref().and_then(Lang::from_code),
+            fallbacks: Some(fallbacks),
+            fallback_models: serde_json::from_value(row.fallback_models)?,
+            reply_threading: row.reply_threading,
+            lead_fields: serde_json::from_value(row.lead_fields)?,
+        })
     }
 }
 
-impl From<Openai> for Value {
-    fn from(value: Openai) -> Self {
-        serde_json::to_value(value).unwrap()
+impl TryFrom<&OpenaiConfig> for SettingsRow {
+    type Error = Error;
+
+    fn try_from(config: &OpenaiConfig) -> Result<Self, Self::Error> {
+        let conversation = config.conversation.clone();
+        let fallbacks = config.fallbacks.clone().unwrap_or_default();
+        Ok(Self {
+            version: config.version,
+            api_key: config.api_key.as_ref().map(serde_json::to_value).transpose()?,
+            model: config.model.clone(),
+            prompt: config.prompt.clone(),
+            max_message_length: config.max_message_length,
+            max_total_tokens_spent: config.max_total_tokens_spent,
+            max_tokens: config.max_tokens.into(),
+            cache_duration: conversation.as_ref().and_then(|conversation| conversation.cache_duration),
+            char_limit: conversation.and_then(|conversation| conversation.char_limit).map(|value| value as i32),
+            answer_pause_min: config.get_answer_pause().0,
+            answer_pause_max: config.get_answer_pause().1,
+            footer: config.get_footer(),
+            sandbox_pause: config.sandbox.as_ref().map(Sandbox::get_pause),
+            language: config.language.map(|lang| lang.code().to_string()),
+            fallback_error: fallbacks.error,
+            fallback_too_long: fallbacks.too_long,
+            fallback_unsupported_media: fallbacks.unsupported_media,
+            fallback_budget_exhausted: fallbacks.budget_exhausted,
+            fallback_models: serde_json::to_value(&config.fallback_models)?,
+            reply_threading: config.reply_threading,
+            lead_fields: serde_json::to_value(&config.lead_fields)?,
+        })
     }
 }
 
 impl OpenaiConfig {
     pub fn get_api_key(&self) -> Option<String> {
-        Some(match self.api_key.clone()? {
+        Some(match self.get_real_api_key()? {
             key if key.len() > 5 => format!("...{}", &key[key.len() - 5..]),
             key => key,
         })
     }
 
     pub fn get_real_api_key(&self) -> Option<String> {
-        self.api_key.clone()
+        match self.api_key.as_ref()?.reveal(self.owner_id) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                tracing::error!(error = %e, "Failed decrypt API key");
+                None
+            }
+        }
     }
 
     pub fn get_model(&self) -> &str {
@@ -122,28 +362,132 @@ impl OpenaiConfig {
         self.max_tokens
     }
 
+    /// Own key of the owner, or the operator default with `true`.
+    fn get_effective_api_key(&self) -> Option<(String, bool)> {
+        match self.get_real_api_key() {
+            Some(api_key) => Some((api_key, false)),
+            None => secrets::default_api_key().map(|api_key| (api_key, true)),
+        }
+    }
+
+    /// Whether answers are generated with the operator key.
+    pub fn uses_shared_key(&self) -> bool {
+        self.get_effective_api_key().is_some_and(|(_, shared_key)| shared_key)
+    }
+
+    /// Removes the owner's key, so the operator default is used.
+    pub fn reset_api_key(&mut self) {
+        self.api_key = None;
+    }
+
     pub async fn set_api_key(&mut self, api_key: String) -> Result<(), &'static str> {
         match Self::validate_api_key(&api_key).await {
             Ok(true) => {
-                self.api_key = Some(api_key);
+                let secret = Secret::seal(&api_key, self.owner_id).map_err(|e| {
+                    tracing::error!(error = %e, "Failed encrypt API key");
+                    "api-key-check-failed"
+                })?;
+                self.api_key = Some(secret);
                 Ok(())
             }
-            Ok(false) => Err("Invalid API key"),
+            Ok(false) => Err("invalid-api-key"),
             Err(e) => {
-                log::error!("Failed check API key:\n{e:?}");
-                Err("Failed check API key")
+                tracing::error!(error = %e, "Failed check API key");
+                Err("api-key-check-failed")
             }
         }
     }
 
     pub fn set_model(&mut self, model: String) -> Result<(), &'static str> {
-        match model.as_str() {
-            "gpt-3.5-turbo" | "gpt-4-turbo" | "gpt-4o" => {
+        match ALLOWED_MODELS.contains(&model.as_str()) {
+            true => {
                 self.model = model;
                 Ok(())
             }
-            _ => Err("Invalid model. Allowed values are: gpt-3.5-turbo, gpt-4-turbo, gpt-4o"),
+            false => Err("invalid-model"),
+        }
+    }
+
+    /// Replaces models the plan does not allow and shortens the history to the plan maximum,
+    /// for settings saved before the plan was assigned or changed.
+    pub fn restrict_to(&mut self, plan: &Plan) {
+        if let Some(model) = plan.get_default_model().filter(|_| !plan.allows_model(&self.model)) {
+            self.model = model.to_string();
+        }
+        self.fallback_models.retain(|fallback| fallback.base_url.is_some() || plan.allows_model(&fallback.model));
+        if let Some(max) = plan.get_max_history_length().filter(|max| self.get_char_limit() > *max) {
+            self.conversation.get_or_insert(Conversation { cache_duration: None, char_limit: None }).char_limit = Some(max);
+        }
+    }
+
+    /// Main model first, then the fallbacks in order.
+    pub fn get_providers(&self) -> Vec<Provider> {
+        let (api_key, shared_key) = self.get_effective_api_key().unzip();
+        let shared_key = shared_key.unwrap_or_default();
+        let mut providers = vec![Provider::openai(&self.model, api_key.clone(), shared_key)];
+        for fallback in &self.fallback_models {
+            providers.push(match &fallback.base_url {
+                // The operator may have removed the endpoint from the allowlist since
+                Some(base_url) if !config::get().allows_base_url(base_url) => continue,
+                Some(base_url) => Provider::self_hosted(&fallback.model, base_url),
+                None => Provider::openai(&fallback.model, api_key.clone(), shared_key),
+            });
+        }
+        providers
+    }
+
+    pub fn get_fallback_models(&self) -> Vec<String> {
+        self.fallback_models.iter()
+            .map(|fallback| match &fallback.base_url {
+                Some(base_url) => format!("{}@{}", fallback.model, base_url),
+                None => fallback.model.clone(),
+            })
+            .collect()
+    }
+
+    /// Accepts `model` for OpenAI models and `model@https://host/v1` for self-hosted ones on the operator allowlist.
+    pub fn set_fallback_models(&mut self, values: Vec<String>) -> Result<(), &'static str> {
+        if values.len() > MAX_FALLBACK_MODELS {
+            return Err("too-many-fallback-models");
+        }
+        let mut fallback_models = vec![];
+        for value in values {
+            fallback_models.push(match value.split_once('@') {
+                Some(("", _)) => return Err("invalid-fallback-model"),
+                Some((model, base_url)) => match config::get().allows_base_url(base_url) {
+                    true => FallbackModel {
+                        model: model.to_string(),
+                        base_url: Some(base_url.trim_end_matches('/').to_string()),
+                    },
+                    false => return Err("fallback-url-not-allowed"),
+                },
+                None if ALLOWED_MODELS.contains(&value.as_str()) => FallbackModel { model: value, base_url: None },
+                _ => return Err("invalid-fallback-model"),
+            });
+        }
+        self.fallback_models = fallback_models;
+        Ok(())
+    }
+
+    pub fn get_lead_fields(&self) -> &[String] {
+        &self.lead_fields
+    }
+
+    pub fn set_lead_fields(&mut self, values: Vec<String>) -> Result<(), &'static str> {
+        if values.len() > MAX_LEAD_FIELDS {
+            return Err("too-many-lead-fields");
+        }
+        if values.iter().any(|value| value.chars().count() > MAX_LEAD_FIELD_LENGTH) {
+            return Err("lead-field-too-long");
+        }
+        let mut lead_fields: Vec<String> = vec![];
+        for value in values {
+            if !lead_fields.contains(&value) {
+                lead_fields.push(value);
+            }
         }
+        self.lead_fields = lead_fields;
+        Ok(())
     }
 
     pub fn set_prompt(&mut self, prompt: String) -> Result<(), &'static str> {
@@ -151,7 +495,7 @@ impl OpenaiConfig {
             self.prompt = Some(prompt);
             Ok(())
         } else {
-            Err("Prompt is too long. Maximum length is 4000 characters")
+            Err("prompt-too-long")
         }
     }
 
@@ -160,7 +504,7 @@ impl OpenaiConfig {
             self.max_message_length = length;
             Ok(())
         } else {
-            Err("Max message length is too long. Maximum is 4000")
+            Err("max-message-length-too-long")
         }
     }
 
@@ -172,17 +516,17 @@ impl OpenaiConfig {
         self.max_tokens = tokens;
     }
 
-    async fn validate_api_key(api_key: &str) -> Result<bool, String> {
+    async fn validate_api_key(api_key: &str) -> Result<bool, Error> {
         dialogue::is_api_key_valid(api_key).await
     }
 
     pub fn set_cache_duration(&mut self, value: i64) -> Result<(), &'static str> {
         if value >= 3_600 {
-            return Err("Maximum duration is 3,600 seconds");
+            return Err("history-timeout-too-long");
         }
 
-        let conversation = self.conversation.get_or_insert_with(
-            || Conversation { cache_duration: None, char_limit: None }
+        let conversation = self.conversation.get_or_insert(
+            Conversation { cache_duration: None, char_limit: None }
         );
         conversation.cache_duration = Some(value);
         Ok(())
@@ -190,10 +534,10 @@ impl OpenaiConfig {
 
     pub fn set_char_limit(&mut self, value: usize) -> Result<(), &'static str> {
         if value >= 10_000 {
-            return Err("Maximum limit is 10,000 symbols");
+            return Err("history-length-too-long");
         }
-        let conversation = self.conversation.get_or_insert_with(
-            || Conversation { cache_duration: None, char_limit: None }
+        let conversation = self.conversation.get_or_insert(
+            Conversation { cache_duration: None, char_limit: None }
         );
         conversation.char_limit = Some(value);
         Ok(())
@@ -216,8 +560,8 @@ impl OpenaiConfig {
         DEFAULT_CHAR_LIMIT
     }
 
-    pub async fn get_manager(&self) -> ConversationManager {
-        let mut manager = ConversationManager::default().await;
+    pub fn get_manager(&self, owner_id: i64) -> Result<ConversationManager, Error> {
+        let mut manager = ConversationManager::for_owner(owner_id)?;
         if let Some(conversation) = self.conversation.clone() {
             if let Some(cache_duration) = conversation.cache_duration {
                 manager = manager.with_cache_duration(cache_duration)
@@ -226,22 +570,22 @@ impl OpenaiConfig {
                 manager = manager.with_char_limit(char_limit)
             }
         }
-        manager
+        Ok(manager)
     }
 
     pub fn set_answer_pause(&mut self, input: &str) -> Result<(), &'static str> {
         let parts: Vec<&str> = input.split(',').map(|s| s.trim()).collect();
         let value = match parts.len() {
             1 => {
-                let value: i32 = parts[0].parse().map_err(|_| "Invalid answer_pause")?;
+                let value: i32 = parts[0].parse().map_err(|_| "invalid-answer-pause")?;
                 (value, value)
             },
             2 => {
-                let value1: i32 = parts[0].parse().map_err(|_| "Invalid answer_pause")?;
-                let value2: i32 = parts[1].parse().map_err(|_| "Invalid answer_pause")?;
+                let value1: i32 = parts[0].parse().map_err(|_| "invalid-answer-pause")?;
+                let value2: i32 = parts[1].parse().map_err(|_| "invalid-answer-pause")?;
                 (value1, value2)
             },
-            _ => return Err("Invalid answer_pause")
+            _ => return Err("invalid-answer-pause")
         };
 
         const MIN_VALUE: i32 = 0;
@@ -249,12 +593,15 @@ impl OpenaiConfig {
 
         let (val1, val2) = value;
 
-        if val1 < MIN_VALUE || val1 > MAX_VALUE || val2 < MIN_VALUE || val2 > MAX_VALUE {
-            return Err("Values must be between 0 and 3600 (inclusive).");
+        if !(MIN_VALUE..=MAX_VALUE).contains(&val1) || !(MIN_VALUE..=MAX_VALUE).contains(&val2) {
+            return Err("answer-pause-out-of-range");
+        }
+        if val1 > val2 {
+            return Err("invalid-answer-pause");
         }
 
         let chatting = self.chatting.get_or_insert_with(
-            || Chatting::default()
+            Chatting::default
         );
 
         chatting.answer_pause = value;
@@ -264,10 +611,10 @@ impl OpenaiConfig {
 
     pub fn set_footer(&mut self, value: Option<String>) -> Result<(), &'static str> {
         if value.clone().is_some_and(|v| v.len() > 40) {
-            return Err("Maximum footer length is 40 symbols");
+            return Err("footer-too-long");
         }
         let chatting = self.chatting.get_or_insert_with(
-            || Chatting::default()
+            Chatting::default
         );
         chatting.footer = value;
         Ok(())
@@ -286,15 +633,51 @@ impl OpenaiConfig {
         };
         Some(DEFAULT_FOOTER.to_string())
     }
-}
 
-impl Openai {
-    pub fn with_config(mut self, config: OpenaiConfig) -> Self {
-        self.config = config;
-        self
+    pub fn get_sandbox(&self) -> Option<&Sandbox> {
+        self.sandbox.as_ref()
     }
-    pub fn with_spent_tokens(mut self, spent_tokens: i64) -> Self {
-        self.spent_tokens = spent_tokens;
-        self
+
+    pub fn set_sandbox(&mut self, value: Option<Sandbox>) {
+        self.sandbox = value;
+    }
+
+    /// Text to send to the customer, `None` to stay silent.
+    pub fn get_fallback(&self, kind: Fallback, lang: Lang) -> Option<String> {
+        let custom = self.fallbacks.clone().and_then(|mut fallbacks| fallbacks.get_mut(kind).clone());
+        match custom {
+            Some(text) if text.is_empty() => None,
+            Some(text) => Some(text),
+            None => kind.default_key().map(|key| t(lang, key)),
+        }
+    }
+
+    /// `None` restores the built-in reply, an empty string makes the bot stay silent.
+    pub fn set_fallback(&mut self, kind: Fallback, value: Option<String>) -> Result<(), &'static str> {
+        if value.as_ref().is_some_and(|v| v.len() > MAX_FALLBACK_LENGTH) {
+            return Err("fallback-too-long");
+        }
+        let fallbacks = self.fallbacks.get_or_insert_with(
+            Fallbacks::default
+        );
+        *fallbacks.get_mut(kind) = value;
+        Ok(())
+    }
+
+    pub fn get_language(&self) -> Option<Lang> {
+        self.language
+    }
+
+    pub fn set_language(&mut self, value: Lang) {
+        self.language = Some(value);
+    }
+
+    /// Whether answers are sent as replies to the customer message.
+    pub fn get_reply_threading(&self) -> bool {
+        self.reply_threading
+    }
+
+    pub fn set_reply_threading(&mut self, value: bool) {
+        self.reply_threading = value;
     }
 }
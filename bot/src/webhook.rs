@@ -0,0 +1,90 @@
+use std::env;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use axum::{Extension, Json, Router};
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use tgbot::api::Client;
+use tgbot::handler::UpdateHandler;
+use tgbot::types::{SetWebhook, Update};
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+use crate::dispatcher::Dispatcher;
+use crate::health;
+
+const DEFAULT_ADDRESS: &str = "0.0.0.0:8080";
+const DEFAULT_PATH: &str = "/webhook";
+const SECRET_TOKEN_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+pub struct WebhookConfig {
+    url: String,
+    address: SocketAddr,
+    path: String,
+    secret_token: String,
+}
+
+impl WebhookConfig {
+    /// Webhook mode is enabled by setting `TG_WEBHOOK_URL`, otherwise the bot falls back to long polling.
+    /// `TG_WEBHOOK_SECRET` is required then, without it anyone could post forged updates.
+    pub fn from_env() -> Option<Self> {
+        let url = env::var("TG_WEBHOOK_URL").ok()?;
+        let address = env::var("TG_WEBHOOK_ADDRESS")
+            .unwrap_or(DEFAULT_ADDRESS.to_string())
+            .parse()
+            .expect("TG_WEBHOOK_ADDRESS must be a socket address");
+        let path = env::var("TG_WEBHOOK_PATH").unwrap_or(DEFAULT_PATH.to_string());
+        let secret_token = env::var("TG_WEBHOOK_SECRET").ok()
+            .filter(|secret_token| !secret_token.is_empty())
+            .expect("TG_WEBHOOK_SECRET must be set in webhook mode");
+        Some(Self { url: url.trim_end_matches('/').to_string(), address, path, secret_token })
+    }
+}
+
+struct State<H> {
+    dispatcher: Arc<Dispatcher<H>>,
+    secret_token: String,
+}
+
+pub async fn run<H, S>(client: &Client, dispatcher: Arc<Dispatcher<H>>, config: WebhookConfig, shutdown: S)
+where
+    H: UpdateHandler + Send + Sync + 'static,
+    S: Future<Output = ()> + Send + 'static,
+{
+    // A single connection makes Telegram deliver updates one by one, keeping the order within a chat
+    let method = SetWebhook::new(format!("{}{}", config.url, config.path))
+        .with_max_connections(1)
+        .with_secret_token(&config.secret_token);
+    health::wait_for("Telegram", || async { Ok(client.execute(method.clone()).await?) }).await;
+
+    let state = Arc::new(State { dispatcher, secret_token: config.secret_token });
+    let router = Router::new()
+        .route(&config.path, post(handle_update::<H>))
+        .layer(Extension(state));
+
+    let listener = TcpListener::bind(config.address).await.expect("Failed to bind webhook address");
+    tracing::info!("Webhook listening on {}", config.address);
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown)
+        .await
+        .expect("Webhook server failed");
+}
+
+async fn handle_update<H>(
+    Extension(state): Extension<Arc<State<H>>>,
+    headers: HeaderMap,
+    Json(update): Json<Update>,
+) -> StatusCode
+where
+    H: UpdateHandler + Send + Sync + 'static,
+{
+    let received = headers.get(SECRET_TOKEN_HEADER).map_or(&[][..], |value| value.as_bytes());
+    if !bool::from(received.ct_eq(state.secret_token.as_bytes())) {
+        tracing::warn!("Rejected webhook request with invalid secret token");
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    // Answer Telegram right away, the update is handled in the background
+    state.dispatcher.dispatch(update);
+    StatusCode::OK
+}